@@ -0,0 +1,190 @@
+//! The Adam7 interlacing of PNG, and reading/writing interlaced PNG files.
+use std::{fs::File, io::{self, BufReader, BufWriter, Write}, path::Path};
+
+use super::raster::Raster;
+use super::transfer::Transfer;
+use super::wire::ChannelLayout;
+
+pub struct Pass {
+    /// First pixel of the pass inside every 8x8 tile
+    pub origin: (u32, u32),
+    /// Distance between two pixels of the pass
+    pub step: (u32, u32),
+    /// Area each pixel covers until later passes refine it
+    pub block: (u32, u32),
+}
+
+pub const PASSES: [Pass; 7] = [
+    Pass { origin: (0, 0), step: (8, 8), block: (8, 8) },
+    Pass { origin: (4, 0), step: (8, 8), block: (4, 8) },
+    Pass { origin: (0, 4), step: (4, 8), block: (4, 4) },
+    Pass { origin: (2, 0), step: (4, 4), block: (2, 4) },
+    Pass { origin: (0, 2), step: (2, 4), block: (2, 2) },
+    Pass { origin: (1, 0), step: (2, 2), block: (1, 2) },
+    Pass { origin: (0, 1), step: (1, 2), block: (1, 1) },
+];
+
+pub struct ImportedPng {
+    pub image: Raster,
+    /// Pixels of every pass, exactly as stored in the file but with 16 bit
+    /// samples little endian. `None` if the file isn't interlaced.
+    pub passes: Option<Vec<Vec<u8>>>,
+}
+
+/// Reads a PNG file, keeping the passes apart if it is interlaced
+pub fn import_png(path: impl AsRef<Path>) -> Result<ImportedPng, png::DecodingError> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Palettes and depths below 8 bits are expanded, 16 bit samples are kept
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let width = reader.info().width;
+    let height = reader.info().height;
+    let interlaced = reader.info().interlaced;
+    let transfer = Transfer::from_png(reader.info());
+    let layout = match reader.output_color_type() {
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => ChannelLayout::Luma16,
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen) => ChannelLayout::LumaA16,
+        (png::ColorType::Rgb, png::BitDepth::Sixteen) => ChannelLayout::Rgb16,
+        (png::ColorType::Rgba, png::BitDepth::Sixteen) => ChannelLayout::Rgba16,
+        (png::ColorType::Grayscale, _) => ChannelLayout::Luma8,
+        (png::ColorType::GrayscaleAlpha, _) => ChannelLayout::LumaA8,
+        (png::ColorType::Rgb, _) => ChannelLayout::Rgb8,
+        (png::ColorType::Rgba, _) => ChannelLayout::Rgba8,
+        // Palettes are expanded by the decoder
+        (png::ColorType::Indexed, _) => unreachable!(),
+    };
+
+    let mut image = Raster::new(width, height, layout);
+    image.set_transfer(transfer);
+    if !interlaced {
+        for y in 0..height {
+            let row = reader.next_row()?.ok_or_else(missing_row)?;
+            for (x, pixel) in read_row(row.data(), layout).chunks_exact(layout.channels()).enumerate() {
+                image.pixel_mut(x as u32, y).copy_from_slice(pixel);
+            }
+        }
+
+        return Ok(ImportedPng {
+            image,
+            passes: None,
+        })
+    }
+
+    let mut passes = vec![Vec::new(); PASSES.len()];
+    for (pass, pixels) in PASSES.iter().zip(passes.iter_mut()) {
+        let columns = width.saturating_sub(pass.origin.0).div_ceil(pass.step.0);
+        if columns == 0 {
+            // The decoder skips empty passes
+            continue
+        }
+        for y in (pass.origin.1..height).step_by(pass.step.1 as usize) {
+            let row = reader.next_row()?.ok_or_else(missing_row)?;
+            let row = read_row(row.data(), layout);
+            for (i, pixel) in row.chunks_exact(layout.channels()).enumerate() {
+                let x = pass.origin.0 + i as u32 * pass.step.0;
+                image.pixel_mut(x, y).copy_from_slice(pixel);
+            }
+            for &sample in row.iter() {
+                layout.push_sample(pixels, sample);
+            }
+        }
+    }
+
+    Ok(ImportedPng {
+        image,
+        passes: Some(passes),
+    })
+}
+
+fn missing_row() -> png::DecodingError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+/// Samples of a row, whose 16 bit samples are big endian
+fn read_row(row: &[u8], layout: ChannelLayout) -> Vec<u32> {
+    match layout.bytes_per_sample() {
+        1 => row.iter().map(|&sample| sample as u32).collect(),
+        _ => row.chunks_exact(2).map(|sample| u16::from_be_bytes([sample[0], sample[1]]) as u32).collect(),
+    }
+}
+
+/// Writes `image` as an Adam7 interlaced PNG, keeping its channels, depth and
+/// transfer curve.
+/// PNG has no float samples, so `image` can't be a float image
+pub fn export_png(image: &Raster, path: impl AsRef<Path>) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let layout = image.layout();
+    assert!(!layout.is_float());
+    // Channels of the image in the order of the file
+    let order: Vec<usize> = match layout.rgb_channels() {
+        Some(rgb) => rgb.into_iter().chain(layout.alpha_channel()).collect(),
+        None => (0..layout.channels()).collect(),
+    };
+    let bytes_per_pixel = layout.bytes_per_pixel();
+
+    let mut scanlines = Vec::new();
+    for pass in PASSES.iter() {
+        let columns: Vec<_> = (pass.origin.0..width).step_by(pass.step.0 as usize).collect();
+        if columns.is_empty() {
+            continue
+        }
+        for y in (pass.origin.1..height).step_by(pass.step.1 as usize) {
+            let mut row = Vec::with_capacity(columns.len() * bytes_per_pixel);
+            for x in columns.iter() {
+                let pixel = image.pixel(*x, y);
+                for &c in order.iter() {
+                    match layout.bytes_per_sample() {
+                        1 => row.push(pixel[c] as u8),
+                        _ => row.extend_from_slice(&(pixel[c] as u16).to_be_bytes()),
+                    }
+                }
+            }
+            // "Sub" filter: every byte minus the same byte of the pixel to its left
+            scanlines.push(1);
+            scanlines.extend(row.iter().enumerate().map(|(i, byte)| {
+                let left = if i >= bytes_per_pixel { row[i - bytes_per_pixel] } else { 0 };
+                byte.wrapping_sub(left)
+            }));
+        }
+    }
+
+    let color_type = match (layout.rgb_channels(), layout.alpha_channel()) {
+        (None, None) => 0,
+        (None, Some(_)) => 4,
+        (Some(_), None) => 2,
+        (Some(_), Some(_)) => 6,
+    };
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[
+        layout.bits_per_sample() as u8,
+        color_type,
+        0, // deflate
+        0, // adaptive filtering
+        1, // Adam7
+    ]);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&[137, 80, 78, 71, 13, 10, 26, 10])?;
+    write_chunk(&mut out, b"IHDR", &header)?;
+    match image.transfer() {
+        // Perceptual rendering intent
+        Transfer::Srgb => write_chunk(&mut out, b"sRGB", &[0])?,
+        Transfer::Gamma(gamma) => write_chunk(&mut out, b"gAMA", &gamma.to_be_bytes())?,
+    }
+    write_chunk(&mut out, b"IDAT", &miniz_oxide::deflate::compress_to_vec_zlib(&scanlines, 6))?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finalize().to_be_bytes())
+}
@@ -0,0 +1,287 @@
+//! Runs a whole transmission without a window or a GPU: every stage goes
+//! from [Encoder::send] straight into [Decoder::receive], and what each of
+//! them cost and achieved is written as CSV or JSON.
+//!
+//! Needs nothing but the codec: `cargo run -p progressive-codec --bin headless -- <image>`
+use std::{fmt::Write as _, fs, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use progressive_codec::{BlurKernel, ColorTransform, Decoder, Encoder, Ladder, Quality, Reconstruction, Scheme};
+
+const USAGE: &str = "\
+usage: headless <image> [options]
+  --scheme <scheme>   blocks[:COARSEST[:STAGES]], adam7, dct[:QUALITY],
+                      wavelet[:LEVELS] or bitplanes[:BITS]
+  --color <color>     none or ycocg[:LAG]
+  --raw               don't arithmetic code the payloads
+  --no-prediction     send samples instead of residuals
+  --reconstruction <mode>
+                      how missing pixels are shown: nearest, bilinear,
+                      bicubic or edge
+  --csv <path>        write the stages as CSV
+  --json <path>       write the stages as JSON
+  --dump <dir>        save the picture at the end of every stage as a PNG
+  --dump-blurred      save the dumps blurred, as shown on screen
+  --blur <kernel>     auto, to follow the rows of every stage, or
+                      RADIUS:SIGMA
+  --blur-known        blur the pixels received exactly too
+With neither --csv nor --json, CSV is written to the standard output.";
+
+struct Options {
+    image: PathBuf,
+    scheme: Option<Scheme>,
+    color: ColorTransform,
+    entropy_coding: bool,
+    residual_prediction: bool,
+    reconstruction: Reconstruction,
+    csv: Option<PathBuf>,
+    json: Option<PathBuf>,
+    dump: Option<PathBuf>,
+    dump_blurred: bool,
+    blur_kernel: BlurKernel,
+    blur_known: bool,
+}
+
+impl Options {
+    fn parse(mut argv: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut image = None;
+        let mut options = Options {
+            image: PathBuf::new(),
+            scheme: None,
+            color: ColorTransform::None,
+            entropy_coding: true,
+            residual_prediction: true,
+            reconstruction: Reconstruction::default(),
+            csv: None,
+            json: None,
+            dump: None,
+            dump_blurred: false,
+            blur_kernel: BlurKernel::default(),
+            blur_known: false,
+        };
+
+        while let Some(arg) = argv.next() {
+            let mut value = |name: &str| argv.next().ok_or(format!("{name} expects a value"));
+            match arg.as_str() {
+                "--scheme" => options.scheme = Some(parse_scheme(&value("--scheme")?)?),
+                "--color" => options.color = parse_color(&value("--color")?)?,
+                "--raw" => options.entropy_coding = false,
+                "--no-prediction" => options.residual_prediction = false,
+                "--reconstruction" => options.reconstruction = parse_reconstruction(&value("--reconstruction")?)?,
+                "--csv" => options.csv = Some(value("--csv")?.into()),
+                "--json" => options.json = Some(value("--json")?.into()),
+                "--dump" => options.dump = Some(value("--dump")?.into()),
+                "--dump-blurred" => options.dump_blurred = true,
+                "--blur" => options.blur_kernel = parse_blur(&value("--blur")?)?,
+                "--blur-known" => options.blur_known = true,
+                other if other.starts_with("--") => return Err(format!("unknown argument {other}")),
+                path if image.is_none() => image = Some(PathBuf::from(path)),
+                path => return Err(format!("unexpected argument {path}")),
+            }
+        }
+
+        options.image = image.ok_or("no image given")?;
+        Ok(options)
+    }
+}
+
+/// `name[:first[:second]]`, with the parameters missing when not given
+fn split_spec(spec: &str) -> Result<(&str, Vec<u8>), String> {
+    let mut parts = spec.split(':');
+    let name = parts.next().unwrap_or_default();
+    let parameters = parts.map(|part| part.parse().map_err(|_| format!("{part} isn't a number between 0 and 255")))
+        .collect::<Result<_, _>>()?;
+    Ok((name, parameters))
+}
+
+fn parse_scheme(spec: &str) -> Result<Scheme, String> {
+    let (name, parameters) = split_spec(spec)?;
+    let scheme = match (name, parameters.as_slice()) {
+        ("blocks", []) => Scheme::Blocks(Ladder::default()),
+        ("blocks", [coarsest]) => Scheme::Blocks(Ladder::new(*coarsest, coarsest + 1)),
+        ("blocks", [coarsest, stages]) => Scheme::Blocks(Ladder::new(*coarsest, *stages)),
+        ("adam7", []) => Scheme::Adam7,
+        ("dct", []) => Scheme::Dct { quality: 75 },
+        ("dct", [quality]) if (1..=100).contains(quality) => Scheme::Dct { quality: *quality },
+        ("wavelet", []) => Scheme::Wavelet { levels: 5 },
+        ("wavelet", [levels]) if (1..=Scheme::MAX_WAVELET_LEVELS).contains(levels) => Scheme::Wavelet { levels: *levels },
+        ("bitplanes", []) => Scheme::BitPlanes { bits_per_stage: 1 },
+        ("bitplanes", [bits]) if (1..=8).contains(bits) => Scheme::BitPlanes { bits_per_stage: *bits },
+        _ => return Err(format!("invalid scheme {spec}")),
+    };
+    Ok(scheme)
+}
+
+fn parse_color(spec: &str) -> Result<ColorTransform, String> {
+    let (name, parameters) = split_spec(spec)?;
+    match (name, parameters.as_slice()) {
+        ("none", []) => Ok(ColorTransform::None),
+        ("ycocg", []) => Ok(ColorTransform::YCoCgR { chroma_lag: 1 }),
+        ("ycocg", [lag]) if *lag <= ColorTransform::MAX_CHROMA_LAG => Ok(ColorTransform::YCoCgR { chroma_lag: *lag }),
+        _ => Err(format!("invalid color transform {spec}")),
+    }
+}
+
+fn parse_reconstruction(spec: &str) -> Result<Reconstruction, String> {
+    match spec {
+        "nearest" => Ok(Reconstruction::Nearest),
+        "bilinear" => Ok(Reconstruction::Bilinear),
+        "bicubic" => Ok(Reconstruction::Bicubic),
+        "edge" => Ok(Reconstruction::EdgeDirected),
+        _ => Err(format!("invalid reconstruction {spec}")),
+    }
+}
+
+fn parse_blur(spec: &str) -> Result<BlurKernel, String> {
+    if spec == "auto" {
+        return Ok(BlurKernel::default())
+    }
+    let invalid = || format!("invalid blur kernel {spec}");
+    let (radius, sigma) = spec.split_once(':').ok_or_else(invalid)?;
+    let radius = radius.parse().map_err(|_| invalid())?;
+    let sigma: f32 = sigma.parse().map_err(|_| invalid())?;
+    if sigma.is_nan() || sigma <= 0.0 {
+        return Err(invalid())
+    }
+    Ok(BlurKernel { auto: false, radius, sigma })
+}
+
+/// What one stage cost and what it achieved
+struct StageReport {
+    stage: u8,
+    /// Of the packet of the stage
+    bytes: usize,
+    /// Of every packet up to the stage
+    total_bytes: usize,
+    encode: Duration,
+    decode: Duration,
+    raw: Quality,
+    blurred: Quality,
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2)
+        },
+    };
+
+    if let Err(e) = transmit(&options) {
+        eprintln!("{e}");
+        std::process::exit(1)
+    }
+}
+
+fn transmit(options: &Options) -> Result<(), String> {
+    let mut server = Encoder::open(&options.image)
+        .map_err(|e| format!("Couldn't open {}: {e}", options.image.display()))?;
+    if let Some(scheme) = options.scheme {
+        server.set_scheme(scheme);
+    }
+    server.set_color(options.color);
+    server.entropy_coding = options.entropy_coding;
+    server.residual_prediction = options.residual_prediction;
+
+    let (width, height) = server.dimensions();
+    let mut client = Decoder::new(width, height);
+    client.reconstruction = options.reconstruction;
+    client.blur_kernel = options.blur_kernel;
+    client.blur_known = options.blur_known;
+    let reference = server.display();
+    if let Some(dir) = &options.dump {
+        fs::create_dir_all(dir).map_err(|e| format!("Couldn't create {}: {e}", dir.display()))?;
+    }
+
+    let mut reports = Vec::new();
+    let mut total_bytes = 0;
+    loop {
+        let stage = server.stage();
+        let start = Instant::now();
+        let Some(packet) = server.send() else { break };
+        let encode = start.elapsed();
+
+        let start = Instant::now();
+        client.receive(&packet).map_err(|e| format!("Stage {stage} was rejected: {e}"))?;
+        let decode = start.elapsed();
+
+        let raw = client.display(false);
+        let blurred = client.display(true);
+        if let Some(dir) = &options.dump {
+            let path = dir.join(format!("stage_{stage:02}.png"));
+            let picture = if options.dump_blurred { &blurred } else { &raw };
+            picture.save_with_format(&path, image::ImageFormat::Png)
+                .map_err(|e| format!("Couldn't save {}: {e}", path.display()))?;
+        }
+
+        total_bytes += packet.len();
+        reports.push(StageReport {
+            stage,
+            bytes: packet.len(),
+            total_bytes,
+            encode,
+            decode,
+            raw: progressive_codec::compare(&reference, &raw),
+            blurred: progressive_codec::compare(&reference, &blurred),
+        });
+    }
+
+    let mut written = false;
+    if let Some(path) = &options.csv {
+        write(path, &csv(&reports))?;
+        written = true;
+    }
+    if let Some(path) = &options.json {
+        write(path, &json(&server, &reports))?;
+        written = true;
+    }
+    if !written {
+        print!("{}", csv(&reports));
+    }
+
+    Ok(())
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Couldn't write {}: {e}", path.display()))
+}
+
+fn csv(reports: &[StageReport]) -> String {
+    let mut csv = String::from("stage,bytes,total_bytes,encode_ms,decode_ms,mse,psnr,ssim,blurred_mse,blurred_psnr,blurred_ssim\n");
+    for report in reports {
+        let _ = writeln!(csv, "{},{},{},{:.3},{:.3},{:.4},{:.4},{:.6},{:.4},{:.4},{:.6}",
+            report.stage, report.bytes, report.total_bytes,
+            millis(report.encode), millis(report.decode),
+            report.raw.mse, report.raw.psnr, report.raw.ssim,
+            report.blurred.mse, report.blurred.psnr, report.blurred.ssim);
+    }
+
+    csv
+}
+
+fn json(server: &Encoder, reports: &[StageReport]) -> String {
+    let quality = |quality: &Quality| format!("{{\"mse\": {}, \"psnr\": {}, \"ssim\": {}}}",
+        number(quality.mse), number(quality.psnr), number(quality.ssim));
+    let stages: Vec<String> = reports.iter().map(|report| format!(
+        "    {{\"stage\": {}, \"bytes\": {}, \"total_bytes\": {}, \"encode_ms\": {}, \"decode_ms\": {}, \"raw\": {}, \"blurred\": {}}}",
+        report.stage, report.bytes, report.total_bytes,
+        number(millis(report.encode)), number(millis(report.decode)),
+        quality(&report.raw), quality(&report.blurred))).collect();
+
+    let (width, height) = server.dimensions();
+    format!("{{\n  \"scheme\": \"{}\",\n  \"layout\": \"{:?}\",\n  \"width\": {},\n  \"height\": {},\n  \"stages\": [\n{}\n  ]\n}}\n",
+        server.scheme().name(), server.layout(), width, height, stages.join(",\n"))
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// JSON has no infinity, which is the PSNR of identical pictures
+fn number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        String::from("null")
+    }
+}
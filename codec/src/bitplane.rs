@@ -0,0 +1,91 @@
+//! Precision-progressive scheme: every stage sends the next most significant
+//! bits of every channel of every pixel.
+//!
+//! The bits of a stage are packed MSB first, pixel by pixel in raster order
+//! and channel by channel inside each pixel. Pixels go in groups of 8 so
+//! every group fills whole bytes; the last group is padded with zeros.
+//!
+//! `bits_per_stage` counts bits of 8 bit samples: deeper samples get
+//! proportionally more per stage, so every depth takes the same stages. The
+//! bits of float samples go sign and exponent first.
+use super::raster::Raster;
+use super::wire::ChannelLayout;
+
+/// Pixels that fill a whole amount of bytes, whatever the bits per stage
+const GROUP: usize = 8;
+
+/// Bit planes sent by `stage`, as (lowest plane, amount of planes)
+fn planes(layout: ChannelLayout, bits_per_stage: u8, stage: u8) -> (u32, u32) {
+    let scale = layout.bytes_per_sample() as u32;
+    let bits_per_stage = bits_per_stage as u32 * scale;
+    let high = layout.bits_per_sample().saturating_sub(stage as u32 * bits_per_stage);
+    let low = high.saturating_sub(bits_per_stage);
+
+    (low, high - low)
+}
+
+pub fn stages(bits_per_stage: u8) -> u8 {
+    8u8.div_ceil(bits_per_stage)
+}
+
+/// Bytes of every group of pixels of `stage`
+pub fn unit_bytes(layout: ChannelLayout, bits_per_stage: u8, stage: u8) -> usize {
+    let (_, count) = planes(layout, bits_per_stage, stage);
+    GROUP * layout.channels() * count as usize / 8
+}
+
+/// Amount of groups of pixels of every stage
+pub fn unit_count(width: u32, height: u32) -> usize {
+    (width as usize * height as usize).div_ceil(GROUP)
+}
+
+pub fn encode_stage(image: &Raster, bits_per_stage: u8, stage: u8) -> Vec<u8> {
+    let layout = image.layout();
+    let (low, count) = planes(layout, bits_per_stage, stage);
+    let (width, height) = image.dimensions();
+    let len = unit_bytes(layout, bits_per_stage, stage);
+    let mut v = Vec::with_capacity(unit_count(width, height) * len);
+    for group in image.samples().chunks(GROUP * layout.channels()) {
+        // The last group stays padded with zeros
+        let mut unit = vec![0; len];
+        for (i, value) in group.iter().enumerate() {
+            for plane in 0..count {
+                let bit = ((value >> (low + count - 1 - plane)) & 1) as u8;
+                let position = i * count as usize + plane as usize;
+                unit[position / 8] |= bit << (7 - position % 8);
+            }
+        }
+        v.extend(unit);
+    }
+
+    v
+}
+
+/// Places the groups of `stage` that start at group `first`. Bits that are
+/// still unknown are set to the middle of their range.
+/// `data` must hold whole groups.
+pub fn decode(image: &mut Raster, bits_per_stage: u8, stage: u8, first: usize, data: &[u8]) {
+    let layout = image.layout();
+    let (low, count) = planes(layout, bits_per_stage, stage);
+    // Planes that were known before this stage
+    let known_mask = !(((1u64 << (low + count)) - 1) as u32) & layout.max_sample();
+    let midpoint = match low {
+        0 => 0,
+        low => 1 << (low - 1),
+    };
+
+    let group_samples = GROUP * layout.channels();
+    let samples = image.samples_mut();
+    for (i, unit) in data.chunks_exact(unit_bytes(layout, bits_per_stage, stage)).enumerate() {
+        let start = (first + i) * group_samples;
+        let end = (start + group_samples).min(samples.len());
+        for (j, value) in samples[start..end].iter_mut().enumerate() {
+            let mut slice = 0;
+            for plane in 0..count as usize {
+                let position = j * count as usize + plane;
+                slice = slice << 1 | ((unit[position / 8] >> (7 - position % 8)) & 1) as u32;
+            }
+            *value = (*value & known_mask) | slice << low | midpoint;
+        }
+    }
+}
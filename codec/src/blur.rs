@@ -0,0 +1,105 @@
+//! Gaussian blur of the picture shown while a transmission is incomplete,
+//! which hides the edges of the blocks that haven't been refined yet.
+use image::RgbaImage;
+use rayon::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlurKernel {
+    /// Whether the radius follows the rows between the pixels of the current
+    /// stage, so coarse stages are smoothed a lot and fine ones barely
+    pub auto: bool,
+    /// Pixels taken on every side of the center, unless `auto`
+    pub radius: u32,
+    /// Standard deviation in pixels, unless `auto`
+    pub sigma: f32,
+}
+
+impl Default for BlurKernel {
+    fn default() -> Self {
+        BlurKernel {
+            auto: true,
+            radius: 2,
+            sigma: 1.0,
+        }
+    }
+}
+
+impl BlurKernel {
+    /// Radius and standard deviation used while the rows of the current stage
+    /// are `step` pixels apart
+    pub fn parameters(&self, step: u32) -> (u32, f32) {
+        if self.auto {
+            // Three standard deviations hold nearly all the weight
+            let radius = step.max(1);
+            (radius, radius as f32 / 3.0)
+        } else {
+            (self.radius, self.sigma)
+        }
+    }
+
+    /// Normalized weights of the separable kernel, from `-radius` to `radius`
+    pub fn weights(&self, step: u32) -> Vec<f32> {
+        let (radius, sigma) = self.parameters(step);
+        // A zero sigma leaves the picture as it is
+        let sigma = sigma.max(f32::EPSILON);
+        let radius = radius as i32;
+        let weights: Vec<f32> = (-radius..=radius)
+            .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let sum: f32 = weights.iter().sum();
+
+        weights.into_iter().map(|weight| weight / sum).collect()
+    }
+
+    /// Blurs the pixels of `image` whose entry in `keep` is false, in raster
+    /// order. The kept ones still weigh on their neighbors
+    pub fn apply_around(&self, image: &mut RgbaImage, step: u32, keep: &[bool]) {
+        let source = image.clone();
+        self.apply(image, step);
+        for ((pixel, original), _) in image.pixels_mut().zip(source.pixels()).zip(keep).filter(|(_, &keep)| keep) {
+            *pixel = *original;
+        }
+    }
+
+    /// Blurs the color of `image` in place, rows first and then columns.
+    /// Pixels past the borders repeat the ones on them, alpha is kept
+    pub fn apply(&self, image: &mut RgbaImage, step: u32) {
+        let weights = self.weights(step);
+        if weights.len() == 1 {
+            return
+        }
+
+        let (width, height) = image.dimensions();
+        let radius = (weights.len() / 2) as i64;
+        let row_len = width as usize * 4;
+
+        let source = image.as_raw().clone();
+        image.par_chunks_exact_mut(row_len).enumerate().for_each(|(y, row)| {
+            let source = &source[y * row_len..][..row_len];
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let sample = |i: i64, c: usize| {
+                    let x = (x as i64 + i).clamp(0, width as i64 - 1) as usize;
+                    source[x * 4 + c] as f32
+                };
+                for (c, value) in pixel[..3].iter_mut().enumerate() {
+                    let sum: f32 = weights.iter().zip(-radius..).map(|(weight, i)| sample(i, c) * weight).sum();
+                    *value = sum.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        });
+
+        let source = image.as_raw().clone();
+        image.par_chunks_exact_mut(row_len).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let sample = |i: i64, c: usize| {
+                    let y = (y as i64 + i).clamp(0, height as i64 - 1) as usize;
+                    source[y * row_len + x * 4 + c] as f32
+                };
+                for (c, value) in pixel[..3].iter_mut().enumerate() {
+                    let sum: f32 = weights.iter().zip(-radius..).map(|(weight, i)| sample(i, c) * weight).sum();
+                    *value = sum.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        });
+    }
+}
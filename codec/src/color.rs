@@ -0,0 +1,80 @@
+//! Reversible YCoCg-R color transform.
+//!
+//! The lifting steps are computed modulo the range of the samples, so every
+//! plane keeps the depth of the image and the transform stays exactly
+//! reversible. Transformed pixels hold Y, Co and Cg in their first three
+//! channels, and alpha (if any) where the image has it.
+use super::raster::Raster;
+use super::wire::ChannelLayout;
+
+/// Whether images in `layout` can be transformed. Float samples can't: the
+/// lifting steps would mix the bits of their exponents and mantissas
+pub fn supports(layout: ChannelLayout) -> bool {
+    layout.rgb_channels().is_some() && !layout.is_float()
+}
+
+/// Channels of a transformed pixel that lag behind
+pub const CHROMA: [usize; 2] = [1, 2];
+
+/// Channels of a transformed pixel refined at full stage density: luma, and
+/// alpha if the layout has it
+pub fn luma_channels(layout: ChannelLayout) -> &'static [usize] {
+    match layout.alpha_channel() {
+        Some(_) => &[0, 3],
+        None => &[0],
+    }
+}
+
+/// Half of a chroma value, taken as signed
+fn half(chroma: u32, max: u32) -> u32 {
+    let sign = chroma & !(max >> 1) & max;
+    chroma >> 1 | sign
+}
+
+fn forward([r, g, b]: [u32; 3], max: u32) -> [u32; 3] {
+    let co = r.wrapping_sub(b) & max;
+    let t = b.wrapping_add(half(co, max)) & max;
+    let cg = g.wrapping_sub(t) & max;
+    let y = t.wrapping_add(half(cg, max)) & max;
+
+    [y, co, cg]
+}
+
+fn inverse([y, co, cg]: [u32; 3], max: u32) -> [u32; 3] {
+    let t = y.wrapping_sub(half(cg, max)) & max;
+    let g = cg.wrapping_add(t) & max;
+    let b = t.wrapping_sub(half(co, max)) & max;
+    let r = b.wrapping_add(co) & max;
+
+    [r, g, b]
+}
+
+/// Transforms an image with color channels
+pub fn to_ycocg(image: &Raster) -> Raster {
+    let layout = image.layout();
+    let rgb = layout.rgb_channels().unwrap();
+    let max = layout.max_sample();
+    let mut transformed = image.clone();
+    for pixel in transformed.pixels_mut() {
+        let ycocg = forward(rgb.map(|c| pixel[c]), max);
+        pixel[..3].copy_from_slice(&ycocg);
+    }
+
+    transformed
+}
+
+/// Undoes [to_ycocg] into `image`
+pub fn from_ycocg(transformed: &Raster, image: &mut Raster) {
+    let layout = image.layout();
+    let rgb = layout.rgb_channels().unwrap();
+    let max = layout.max_sample();
+    for (pixel, transformed) in image.pixels_mut().zip(transformed.pixels()) {
+        let values = inverse([transformed[0], transformed[1], transformed[2]], max);
+        for (c, value) in rgb.into_iter().zip(values) {
+            pixel[c] = value;
+        }
+        if let Some(alpha) = layout.alpha_channel() {
+            pixel[alpha] = transformed[alpha];
+        }
+    }
+}
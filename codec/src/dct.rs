@@ -0,0 +1,483 @@
+//! Progressive JPEG-like scheme.
+//!
+//! The image is converted to YCbCr (grayscale images only have Y, and alpha
+//! is kept as one more component), split in 8x8 blocks, transformed with a
+//! DCT and quantized. 16 bit samples use the same tables scaled by 256, and
+//! float samples take 1.0 as the 255 of 8 bit samples.
+//! Every stage (scan) sends a band of coefficients of every block, first
+//! without their lowest bits (spectral selection) and later one bit at a time
+//! (successive approximation).
+//!
+//! First scans send every coefficient as a `u16` holding its magnitude shifted
+//! left by one, with the sign in the lowest bit. Refinement scans send one bit
+//! per coefficient, with the bits of every block padded to a whole byte.
+use std::ops::RangeInclusive;
+
+use super::raster::{Raster, MAX_CHANNELS};
+use super::wire::ChannelLayout;
+
+/// No bit of the coefficient is known yet
+const UNKNOWN: u8 = u8::MAX;
+
+pub struct Scan {
+    /// Coefficients sent, in zigzag order
+    pub band: RangeInclusive<usize>,
+    /// Lowest bits of the coefficients that are left out
+    pub shift: u8,
+    /// Whether the scan sends the bit `shift` of coefficients that were
+    /// already sent, instead of sending them for the first time
+    pub refine: bool,
+}
+
+pub const SCANS: [Scan; 6] = [
+    Scan { band: 0..=0, shift: 1, refine: false },
+    Scan { band: 1..=5, shift: 2, refine: false },
+    Scan { band: 6..=63, shift: 2, refine: false },
+    Scan { band: 1..=63, shift: 1, refine: true },
+    Scan { band: 0..=0, shift: 0, refine: true },
+    Scan { band: 1..=63, shift: 0, refine: true },
+];
+
+impl Scan {
+    /// Bytes each block takes in the payload. There is a component per
+    /// channel of `layout`
+    pub fn block_bytes(&self, layout: ChannelLayout) -> usize {
+        let coefficients = self.band.clone().count() * layout.channels();
+        if self.refine {
+            coefficients.div_ceil(8)
+        } else {
+            coefficients * 2
+        }
+    }
+}
+
+/// Amount of blocks of an image, in raster order
+pub fn block_count(width: u32, height: u32) -> usize {
+    width.div_ceil(8) as usize * height.div_ceil(8) as usize
+}
+
+/// Quantized coefficients of every block, in zigzag order
+pub struct DctCoefficients {
+    pub quality: u8,
+    layout: ChannelLayout,
+    /// A component per channel of `layout` for every block
+    blocks: Vec<[i32; 64]>,
+}
+
+impl DctCoefficients {
+    pub fn new(image: &Raster, quality: u8) -> DctCoefficients {
+        let layout = image.layout();
+        let components = layout.channels();
+        let tables = quantization_tables(quality);
+        let depth_scale = depth_scale(layout);
+        let center = center(layout);
+        let (width, height) = image.dimensions();
+        let blocks_x = width.div_ceil(8);
+        let blocks_y = height.div_ceil(8);
+
+        let mut blocks = Vec::with_capacity(block_count(width, height) * components);
+        for block_y in 0..blocks_y {
+            for block_x in 0..blocks_x {
+                let mut samples = [[0.0; 64]; MAX_CHANNELS];
+                for y in 0..8 {
+                    for x in 0..8 {
+                        // Edges are padded by repeating the last pixel
+                        let px = (block_x * 8 + x).min(width - 1);
+                        let py = (block_y * 8 + y).min(height - 1);
+                        let ycc = to_components(layout, image.pixel(px, py));
+                        for (c, sample) in ycc.iter().enumerate().take(components) {
+                            samples[c][(y * 8 + x) as usize] = sample - center;
+                        }
+                    }
+                }
+
+                for (c, samples) in samples.iter().enumerate().take(components) {
+                    let transformed = fdct(samples);
+                    let table = &tables[component_table(layout, c)];
+                    let mut coefficients = [0; 64];
+                    for (k, coefficient) in coefficients.iter_mut().enumerate() {
+                        let natural = ZIGZAG[k];
+                        *coefficient = (transformed[natural] / (table[natural] as f32 * depth_scale)).round() as i32;
+                    }
+                    blocks.push(coefficients);
+                }
+            }
+        }
+
+        DctCoefficients {
+            quality,
+            layout,
+            blocks,
+        }
+    }
+
+    pub fn encode_scan(&self, scan: &Scan) -> Vec<u8> {
+        let components = self.layout.channels();
+        let block_count = self.blocks.len() / components;
+        let mut v = Vec::with_capacity(block_count * scan.block_bytes(self.layout));
+        for block in self.blocks.chunks_exact(components) {
+            if scan.refine {
+                let mut bits = BitWriter::default();
+                for coefficients in block {
+                    for k in scan.band.clone() {
+                        bits.push((coefficients[k].unsigned_abs() >> scan.shift) & 1 == 1);
+                    }
+                }
+                v.extend(bits.finish());
+            } else {
+                for coefficients in block {
+                    for k in scan.band.clone() {
+                        let coefficient = coefficients[k];
+                        let magnitude = coefficient.unsigned_abs() >> scan.shift;
+                        // Only bright float images can go past what fits
+                        let value = (magnitude.min(u16::MAX as u32 >> 1) << 1) as u16 | (coefficient < 0) as u16;
+                        v.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        v
+    }
+}
+
+#[derive(Clone, Copy)]
+struct KnownCoefficient {
+    /// Bits of the magnitude received so far
+    magnitude: u32,
+    negative: bool,
+    /// Low bits of the magnitude that are still unknown
+    unknown_bits: u8,
+}
+
+impl KnownCoefficient {
+    const NOTHING: KnownCoefficient = KnownCoefficient {
+        magnitude: 0,
+        negative: false,
+        unknown_bits: UNKNOWN,
+    };
+
+    /// Best guess of the quantized coefficient: unknown bits are assumed to
+    /// be halfway through their range
+    fn estimate(&self) -> f32 {
+        if self.unknown_bits == UNKNOWN {
+            return 0.0
+        }
+        let mut magnitude = (self.magnitude << self.unknown_bits) as f32;
+        if self.magnitude > 0 {
+            magnitude += ((1 << self.unknown_bits) - 1) as f32 / 2.0;
+        }
+
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+}
+
+/// Keeps what is known of every coefficient and turns it back into pixels
+pub struct DctDecoder {
+    width: u32,
+    height: u32,
+    layout: ChannelLayout,
+    tables: [[u16; 64]; 2],
+    /// A component per channel of `layout` for every block
+    blocks: Vec<[KnownCoefficient; 64]>,
+}
+
+impl DctDecoder {
+    /// Pixels are drawn in `layout`
+    pub fn new(width: u32, height: u32, layout: ChannelLayout, quality: u8) -> DctDecoder {
+        DctDecoder {
+            width,
+            height,
+            layout,
+            tables: quantization_tables(quality),
+            blocks: vec![[KnownCoefficient::NOTHING; 64]; block_count(width, height) * layout.channels()],
+        }
+    }
+
+    /// Decodes the part of a scan that starts at block `first_block`, and
+    /// redraws the affected blocks into `image`. `data` must hold whole blocks.
+    pub fn decode(&mut self, scan: &Scan, first_block: usize, data: &[u8], image: &mut Raster) {
+        let components = self.layout.channels();
+        for (i, block_data) in data.chunks_exact(scan.block_bytes(self.layout)).enumerate() {
+            let block = first_block + i;
+            let components = &mut self.blocks[block * components..(block + 1) * components];
+            if scan.refine {
+                let mut bits = BitReader::new(block_data);
+                for coefficients in components.iter_mut() {
+                    for k in scan.band.clone() {
+                        let coefficient = &mut coefficients[k];
+                        coefficient.magnitude = coefficient.magnitude << 1 | bits.next() as u32;
+                        coefficient.unknown_bits = scan.shift;
+                    }
+                }
+            } else {
+                let mut values = block_data.chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]]));
+                for coefficients in components.iter_mut() {
+                    for k in scan.band.clone() {
+                        let value = values.next().unwrap();
+                        coefficients[k] = KnownCoefficient {
+                            magnitude: (value >> 1) as u32,
+                            negative: value & 1 == 1,
+                            unknown_bits: scan.shift,
+                        };
+                    }
+                }
+            }
+
+            self.draw_block(block, image);
+        }
+    }
+
+    fn draw_block(&self, block: usize, image: &mut Raster) {
+        let blocks_x = self.width.div_ceil(8) as usize;
+        let origin_x = (block % blocks_x) as u32 * 8;
+        let origin_y = (block / blocks_x) as u32 * 8;
+        let components = self.layout.channels();
+        let center = center(self.layout);
+        let depth_scale = depth_scale(self.layout);
+
+        let mut samples = [[0.0; 64]; MAX_CHANNELS];
+        for (c, coefficients) in self.blocks[block * components..(block + 1) * components].iter().enumerate() {
+            let table = &self.tables[component_table(self.layout, c)];
+            let mut dequantized = [0.0; 64];
+            for (k, coefficient) in coefficients.iter().enumerate() {
+                let natural = ZIGZAG[k];
+                dequantized[natural] = coefficient.estimate() * table[natural] as f32 * depth_scale;
+            }
+            samples[c] = idct(&dequantized);
+        }
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let px = origin_x + x;
+                let py = origin_y + y;
+                if px >= self.width || py >= self.height {
+                    continue
+                }
+                let i = (y * 8 + x) as usize;
+                let ycc = std::array::from_fn(|c| samples[c][i] + center);
+                from_components(self.layout, ycc, image.pixel_mut(px, py));
+            }
+        }
+    }
+}
+
+/// Middle of the range of the samples, which the DCT is centered on
+fn center(layout: ChannelLayout) -> f32 {
+    if layout.is_float() {
+        0.5
+    } else {
+        (layout.max_sample() as u64 + 1) as f32 / 2.0
+    }
+}
+
+/// Luma table for Y and alpha, chroma table for Cb and Cr
+fn component_table(layout: ChannelLayout, component: usize) -> usize {
+    match (layout.rgb_channels(), component) {
+        (Some(_), 1 | 2) => 1,
+        _ => 0,
+    }
+}
+
+/// Components of a pixel: Y, Cb and Cr (or just Y), then alpha
+fn to_components(layout: ChannelLayout, pixel: &[u32]) -> [f32; MAX_CHANNELS] {
+    let mut components = [0.0; MAX_CHANNELS];
+    let mut alpha_component = 1;
+    match layout.rgb_channels() {
+        Some(rgb) => {
+            let [r, g, b] = rgb.map(|c| layout.sample_value(pixel[c]));
+            let center = center(layout);
+            components[0] = 0.299 * r + 0.587 * g + 0.114 * b;
+            components[1] = -0.168736 * r - 0.331264 * g + 0.5 * b + center;
+            components[2] = 0.5 * r - 0.418688 * g - 0.081312 * b + center;
+            alpha_component = 3;
+        },
+        None => components[0] = layout.sample_value(pixel[0]),
+    }
+    if let Some(alpha) = layout.alpha_channel() {
+        components[alpha_component] = layout.sample_value(pixel[alpha]);
+    }
+
+    components
+}
+
+/// Undoes [to_components] into `pixel`
+fn from_components(layout: ChannelLayout, components: [f32; MAX_CHANNELS], pixel: &mut [u32]) {
+    let to_sample = |value: f32| layout.sample_of_value(value);
+    let mut alpha_component = 1;
+    match layout.rgb_channels() {
+        Some(rgb) => {
+            let center = center(layout);
+            let [y, cb, cr] = [components[0], components[1] - center, components[2] - center];
+            let values = [
+                y + 1.402 * cr,
+                y - 0.344136 * cb - 0.714136 * cr,
+                y + 1.772 * cb,
+            ];
+            for (c, value) in rgb.into_iter().zip(values) {
+                pixel[c] = to_sample(value);
+            }
+            alpha_component = 3;
+        },
+        None => pixel[0] = to_sample(components[0]),
+    }
+    if let Some(alpha) = layout.alpha_channel() {
+        pixel[alpha] = to_sample(components[alpha_component]);
+    }
+}
+
+/// cos((2x + 1) * u * pi / 16), scaled by C(u)/2
+fn dct_basis() -> [[f32; 8]; 8] {
+    let mut basis = [[0.0; 8]; 8];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let scale = if u == 0 {
+            std::f32::consts::FRAC_1_SQRT_2 / 2.0
+        } else {
+            0.5
+        };
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = scale * (((2 * x + 1) * u) as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+
+    basis
+}
+
+fn fdct(samples: &[f32; 64]) -> [f32; 64] {
+    let basis = dct_basis();
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| basis[u][x] * samples[y * 8 + x]).sum();
+        }
+    }
+    let mut out = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| basis[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+
+    out
+}
+
+fn idct(coefficients: &[f32; 64]) -> [f32; 64] {
+    let basis = dct_basis();
+    let mut columns = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            columns[y * 8 + u] = (0..8).map(|v| basis[v][y] * coefficients[v * 8 + u]).sum();
+        }
+    }
+    let mut out = [0.0; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            out[y * 8 + x] = (0..8).map(|u| basis[u][x] * columns[y * 8 + u]).sum();
+        }
+    }
+
+    out
+}
+
+/// Natural (row major) index of every zigzag position
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+const LUMA_TABLE: [u16; 64] = [
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103,  99,
+];
+
+const CHROMA_TABLE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Factor of the quantization tables for the depth of `layout`
+fn depth_scale(layout: ChannelLayout) -> f32 {
+    if layout.is_float() {
+        1.0 / 255.0
+    } else {
+        (layout.max_sample() as u64 + 1) as f32 / 256.0
+    }
+}
+
+/// Luma and chroma tables scaled like libjpeg does for `quality` (1 to 100)
+fn quantization_tables(quality: u8) -> [[u16; 64]; 2] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    let scaled = |table: &[u16; 64]| table.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u16);
+
+    [scaled(&LUMA_TABLE), scaled(&CHROMA_TABLE)]
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used of the last byte, 0 when it is full
+    used: u8,
+}
+
+impl BitWriter {
+    fn push(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes,
+            position: 0,
+        }
+    }
+
+    fn next(&mut self) -> bool {
+        let bit = self.bytes[self.position / 8] & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        bit
+    }
+}
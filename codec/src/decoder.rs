@@ -0,0 +1,758 @@
+//! Receiving side of a transmission: places every unit of the packet stream
+//! as soon as it arrives.
+use std::{fmt, io, path::Path, time::Duration};
+
+use image::{imageops, RgbaImage};
+
+use crate::adam7;
+use crate::bitplane;
+use crate::blur::BlurKernel;
+use crate::color;
+use crate::dct::{self, DctDecoder};
+use crate::entropy::EntropyDecoder;
+use crate::metrics::{self, StageMetrics};
+use crate::predict;
+use crate::raster::{self, Raster, Rect, ALL_CHANNELS};
+use crate::reconstruct::{self, Lattice, Reconstruction};
+use crate::scheme::{self, ColorTransform, Scheme, SendStage, StageGrid};
+use crate::sequence::{self, SequenceFormat};
+use crate::tonemap::ToneMapping;
+use crate::transfer::Transfer;
+use crate::wavelet::{self, WaveletDecoder};
+use crate::wire::{ChannelLayout, Coding, PacketHeader, PacketStream, Predictor, WireError};
+
+/// Rebuilds the image sent by an [Encoder](crate::Encoder) from its packet
+/// stream, showing what has arrived so far at any moment.
+pub struct Decoder {
+    /// In the layout and transfer curve of the transmission
+    receiving_image: Raster,
+    /// Part of `receiving_image` that changed since [Decoder::take_dirty]
+    dirty: Option<Rect>,
+    /// Pictures drawn again inside `dirty` by [Decoder::take_dirty]
+    view: View,
+    /// Channels of every pixel received with their true value, in raster
+    /// order. The rest of the picture is estimated from them
+    known: Vec<u8>,
+    send_stage: SendStage,
+    stream: PacketStream,
+    /// Whether the header of the packet being received was already validated
+    header_accepted: bool,
+    /// Units (pixels, blocks or coefficients) of the current stage that have
+    /// already been placed
+    stage_progress: usize,
+    decoder: StageDecoder,
+    /// Decoder of the current stage, when its payload is arithmetic coded
+    entropy: Option<EntropyDecoder>,
+    pub blur: bool,
+    pub blur_kernel: BlurKernel,
+    /// Whether blurring also smears the pixels known exactly
+    pub blur_known: bool,
+    /// How the pixels that haven't arrived are shown
+    pub reconstruction: Reconstruction,
+    /// How float images are shown
+    pub tone_mapping: ToneMapping,
+    /// Whether the picture at the end of every stage is kept for
+    /// [Decoder::save_sequence]. Off by default, since it costs a picture per stage
+    pub record_stages: bool,
+    /// Picture at the end of every stage so far, before blurring
+    stage_frames: Vec<StageFrame>,
+    /// Whether the picture at the end of every stage is compared to the image
+    /// given to [Decoder::set_reference]. Off by default
+    pub measure_quality: bool,
+    /// Image the server sends, only used to measure the quality
+    reference: Option<Raster>,
+    metrics: Vec<StageMetrics>,
+    /// Bytes of the packet stream received since the last clear
+    bytes_received: usize,
+}
+
+impl Decoder {
+    pub fn new(width: u32, height: u32) -> Decoder {
+        Decoder {
+            receiving_image: Raster::new(width, height, ChannelLayout::Rgba8),
+            dirty: Some(Rect::whole(width, height)),
+            view: View::new(width, height),
+            known: vec![0; width as usize * height as usize],
+            send_stage: SendStage::init(Scheme::default(), ColorTransform::default()),
+            stream: PacketStream::default(),
+            header_accepted: false,
+            stage_progress: 0,
+            decoder: StageDecoder::Pixels,
+            entropy: None,
+            blur: false,
+            blur_kernel: BlurKernel::default(),
+            blur_known: false,
+            reconstruction: Reconstruction::default(),
+            tone_mapping: ToneMapping::default(),
+            record_stages: false,
+            stage_frames: Vec::new(),
+            measure_quality: false,
+            reference: None,
+            metrics: Vec::new(),
+            bytes_received: 0,
+        }
+    }
+
+    /// Feeds bytes of the packet stream produced by [Encoder::send](crate::Encoder::send).
+    ///
+    /// `data` may hold any part of the stream, every unit of a stage is placed
+    /// as soon as it arrives. A packet that isn't the stage the client expects
+    /// is discarded without touching `receiving_image`.
+    pub fn receive(&mut self, data: &[u8]) -> Result<(), ReceiveError> {
+        self.stream.push(data);
+        self.bytes_received += data.len();
+
+        let mut updated = false;
+        let mut error = None;
+        loop {
+            let header = match self.stream.header() {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                Err(e) => {
+                    error.get_or_insert(e.into());
+                    break
+                },
+            };
+            // A packet whose payload arrives in several pieces is only
+            // checked once, the transmission starts with its first piece
+            if !self.header_accepted {
+                if let Err(e) = self.validate(&header) {
+                    self.stream.skip_packet();
+                    error.get_or_insert(e);
+                    continue
+                }
+                self.header_accepted = true;
+            }
+
+            let layout = self.receiving_image.layout();
+            let unit = scheme::stage_unit(&self.send_stage, layout);
+            let payload = match header.coding {
+                Coding::Raw => self.stream.take_payload(unit),
+                Coding::Arithmetic => {
+                    let coded = self.stream.take_payload(1);
+                    let last = self.stream.payload_left() == 0;
+                    let (width, height) = self.receiving_image.dimensions();
+                    let units_left = scheme::stage_units(&self.send_stage, layout, width, height) - self.stage_progress;
+                    let decoder = self.entropy.get_or_insert_with(|| EntropyDecoder::new(unit));
+                    decoder.feed(&coded, last);
+                    decoder.decode(units_left)
+                },
+            };
+            if !payload.is_empty() {
+                self.place(&payload, header.predictor);
+                updated = true;
+            }
+
+            if self.stream.finish_packet() {
+                self.header_accepted = false;
+                // The lattices and the blur of the next stage change every pixel
+                self.redraw();
+                let stage = self.send_stage.index();
+                self.send_stage.next().unwrap();
+                self.stage_progress = 0;
+                self.entropy = None;
+                let measure = self.measure_quality && self.reference.is_some();
+                if self.record_stages || measure {
+                    self.draw();
+                    let display = self.picture();
+                    if measure {
+                        self.measure(stage, &display);
+                    }
+                    if self.record_stages {
+                        self.stage_frames.push(StageFrame {
+                            step: self.blur_step(),
+                            known: self.known_mask(),
+                            picture: display,
+                        });
+                    }
+                }
+            } else {
+                break
+            }
+        }
+
+        if updated {
+            self.draw();
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Compares the picture at the end of `stage` to `reference`
+    fn measure(&mut self, stage: u8, display: &RgbaImage) {
+        let Some(reference) = &self.reference else {
+            return
+        };
+        let reference = reference.to_display(&self.tone_mapping);
+        let mut blurred = display.clone();
+        if !self.send_stage.is_end() {
+            self.smooth(&mut blurred, self.blur_step(), &self.known_mask());
+        }
+
+        self.metrics.push(StageMetrics {
+            stage,
+            bytes_received: self.bytes_received - self.stream.buffered(),
+            raw: metrics::compare(&reference, display),
+            blurred: metrics::compare(&reference, &blurred),
+        });
+    }
+
+    /// Brings `receiving_image` up to date with decoders that don't place
+    /// their units straight into it
+    fn draw(&mut self) {
+        match &self.decoder {
+            StageDecoder::Wavelet(decoder) => decoder.draw(&mut self.receiving_image),
+            StageDecoder::YCoCg { working } => color::from_ycocg(working, &mut self.receiving_image),
+            _ => (),
+        }
+    }
+
+    /// Places the next units of the current stage
+    fn place(&mut self, payload: &[u8], predictor: Predictor) {
+        let stage = self.send_stage.index();
+        let layout = self.receiving_image.layout();
+        let (width, height) = self.receiving_image.dimensions();
+        // Schemes that don't send pixels may change any of them
+        let mut changed = Some(Rect::whole(width, height));
+        let placed = match &mut self.decoder {
+            StageDecoder::Pixels => {
+                let grid = self.send_stage.grid().unwrap();
+                let channels = &ALL_CHANNELS[..layout.channels()];
+                let placed = place_pixels(&mut self.receiving_image, &mut self.known, grid, channels, predictor, self.stage_progress, payload);
+                changed = rows_placed(grid, width, height, self.stage_progress, placed);
+                placed
+            },
+            StageDecoder::YCoCg { working } => {
+                let mut samples = payload;
+                let mut placed = 0;
+                changed = None;
+                // Planes that come before the ones of this payload are skipped
+                let mut skip = self.stage_progress;
+                for (grid, channel) in scheme::ycocg_planes(&self.send_stage, layout) {
+                    let len = grid.sample_count(width, height);
+                    if skip >= len {
+                        skip -= len;
+                        continue
+                    }
+                    let bytes = ((len - skip) * layout.bytes_per_sample()).min(samples.len());
+                    let (plane, rest) = samples.split_at(bytes);
+                    let plane_placed = place_pixels(working, &mut self.known, grid, &[channel], predictor, skip, plane);
+                    if let Some(rows) = rows_placed(grid, width, height, skip, plane_placed) {
+                        changed = Some(changed.map_or(rows, |changed| changed.union(rows)));
+                    }
+                    placed += plane_placed;
+                    samples = rest;
+                    skip = 0;
+                    if samples.is_empty() {
+                        break
+                    }
+                }
+                placed
+            },
+            StageDecoder::Dct(decoder) => {
+                let scan = &dct::SCANS[stage as usize];
+                decoder.decode(scan, self.stage_progress, payload, &mut self.receiving_image);
+                payload.len() / scan.block_bytes(layout)
+            },
+            StageDecoder::Wavelet(decoder) => {
+                decoder.decode(stage, self.stage_progress, payload);
+                payload.len() / wavelet::unit_bytes(layout)
+            },
+            StageDecoder::BitPlanes { bits_per_stage } => {
+                let bits_per_stage = *bits_per_stage;
+                bitplane::decode(&mut self.receiving_image, bits_per_stage, stage, self.stage_progress, payload);
+                payload.len() / bitplane::unit_bytes(layout, bits_per_stage, stage)
+            },
+        };
+        self.stage_progress += placed;
+        if let Some(changed) = changed {
+            self.dirty = Some(self.dirty.map_or(changed, |dirty| dirty.union(changed)));
+        }
+    }
+
+    fn validate(&mut self, header: &PacketHeader) -> Result<(), ReceiveError> {
+        let (width, height) = self.receiving_image.dimensions();
+        let color = ColorTransform::from_wire(header.color, header.color_param)
+            .ok_or(ReceiveError::UnknownColorTransform(header.color))?;
+        let scheme = header.stage_count.checked_sub(color.extra_stages())
+            .and_then(|stages| Scheme::from_wire(header.scheme, header.scheme_param, stages))
+            .ok_or(ReceiveError::UnknownScheme(header.scheme))?;
+        // A new transmission may use a different scheme and layout, which are
+        // only taken once the whole header is known to be right
+        let starts = self.send_stage.index() == 0 && self.stage_progress == 0;
+        let (send_stage, layout, transfer) = if starts {
+            if color != ColorTransform::None && !color::supports(header.layout) {
+                return Err(ReceiveError::UnsupportedColorTransform(color))
+            }
+            (SendStage::init(scheme, color), header.layout, Transfer::from_wire(header.transfer))
+        } else {
+            (self.send_stage, self.receiving_image.layout(), self.receiving_image.transfer())
+        };
+        if send_stage.is_end() {
+            return Err(ReceiveError::AlreadyComplete)
+        }
+        if scheme != send_stage.scheme() {
+            return Err(ReceiveError::SchemeMismatch {
+                expected: send_stage.scheme(),
+                got: scheme,
+            })
+        }
+        if color != send_stage.color() {
+            return Err(ReceiveError::ColorMismatch {
+                expected: send_stage.color(),
+                got: color,
+            })
+        }
+        if header.width != width || header.height != height {
+            return Err(ReceiveError::SizeMismatch {
+                expected: (width, height),
+                got: (header.width, header.height),
+            })
+        }
+        if header.layout != layout {
+            return Err(ReceiveError::LayoutMismatch(header.layout))
+        }
+        if Transfer::from_wire(header.transfer) != transfer {
+            return Err(ReceiveError::TransferMismatch(Transfer::from_wire(header.transfer)))
+        }
+        if header.predictor != Predictor::None && !send_stage.is_spatial() {
+            return Err(ReceiveError::UnsupportedPredictor(header.predictor))
+        }
+        if header.stage != send_stage.index() {
+            return Err(ReceiveError::UnexpectedStage {
+                expected: send_stage.index(),
+                got: header.stage,
+            })
+        }
+        let expected_len = scheme::stage_unit(&send_stage, layout) * scheme::stage_units(&send_stage, layout, width, height);
+        if header.raw_len as usize != expected_len {
+            return Err(ReceiveError::PayloadLength {
+                expected: expected_len,
+                got: header.raw_len as usize,
+            })
+        }
+        if header.coding == Coding::Raw && header.payload_len != header.raw_len {
+            return Err(ReceiveError::PayloadLength {
+                expected: header.raw_len as usize,
+                got: header.payload_len as usize,
+            })
+        }
+
+        if starts {
+            if layout != self.receiving_image.layout() {
+                self.receiving_image = Raster::new(width, height, layout);
+            }
+            self.receiving_image.set_transfer(transfer);
+            self.redraw();
+            self.send_stage = send_stage;
+            self.decoder = StageDecoder::new(scheme, color, layout, width, height);
+        }
+
+        Ok(())
+    }
+
+    /// Forgets everything received, waiting for a new transmission
+    pub fn clear(&mut self) {
+        let (width, height) = self.receiving_image.dimensions();
+        self.start_over(width, height);
+    }
+
+    /// Forgets everything received, waiting for a new transmission at
+    /// `width` by `height`
+    fn start_over(&mut self, width: u32, height: u32) {
+        let mut receiving_image = Raster::new(width, height, self.receiving_image.layout());
+        receiving_image.set_transfer(self.receiving_image.transfer());
+
+        self.receiving_image = receiving_image;
+        self.dirty = Some(Rect::whole(width, height));
+        self.known = vec![0; width as usize * height as usize];
+        self.send_stage = SendStage::init(self.send_stage.scheme(), self.send_stage.color());
+        self.stream.clear();
+        self.header_accepted = false;
+        self.stage_progress = 0;
+        self.decoder = StageDecoder::Pixels;
+        self.entropy = None;
+        self.stage_frames.clear();
+        self.metrics.clear();
+        self.bytes_received = 0;
+    }
+
+    /// Sets the image the quality of every stage is measured against
+    pub fn set_reference(&mut self, reference: Raster) {
+        self.reference = Some(reference);
+    }
+
+    /// Quality of every stage received since the last clear
+    pub fn metrics(&self) -> &[StageMetrics] {
+        &self.metrics
+    }
+
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.send_stage.is_end()
+    }
+
+    /// Starts over at new dimensions
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.start_over(width, height);
+    }
+
+    /// Width and height of the image being received, in pixels
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.receiving_image.dimensions()
+    }
+
+    /// Channels and depth of the current transmission
+    pub fn layout(&self) -> ChannelLayout {
+        self.receiving_image.layout()
+    }
+
+    /// Everything received so far, in the layout and transfer curve of the
+    /// transmission
+    pub fn image(&self) -> &Raster {
+        &self.receiving_image
+    }
+
+    /// Saves `receiving_image` as an interlaced PNG, with the channels and
+    /// depth it was sent with. Float images are saved tone mapped
+    pub fn export_png(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if self.receiving_image.layout().is_float() {
+            let display = self.receiving_image.to_display(&self.tone_mapping);
+            adam7::export_png(&Raster::from_display(&display), path)
+        } else {
+            adam7::export_png(&self.receiving_image, path)
+        }
+    }
+
+    /// Saves the picture shown right now as a PNG, blurred if `with_blur` and
+    /// the transmission isn't complete, as on screen
+    pub fn save_snapshot(&self, path: impl AsRef<Path>, with_blur: bool) -> io::Result<()> {
+        self.display(with_blur).save_with_format(path, image::ImageFormat::Png).map_err(io::Error::other)
+    }
+
+    /// Stages recorded so far
+    pub fn recorded_stages(&self) -> usize {
+        self.stage_frames.len()
+    }
+
+    /// Saves the picture at the end of every stage recorded so far. If
+    /// `with_blur`, all of them are blurred but the one of a complete
+    /// transmission
+    pub fn save_sequence(&self, path: impl AsRef<Path>, format: SequenceFormat, delay: Duration, with_blur: bool) -> io::Result<()> {
+        let mut frames: Vec<RgbaImage> = self.stage_frames.iter().map(|frame| frame.picture.clone()).collect();
+        if with_blur {
+            let blurred = if self.is_complete() { frames.len().saturating_sub(1) } else { frames.len() };
+            for (picture, frame) in frames[..blurred].iter_mut().zip(&self.stage_frames) {
+                self.smooth(picture, frame.step, &frame.known);
+            }
+        }
+
+        sequence::save(&frames, format, delay, path)
+    }
+
+    /// The picture as shown on screen, blurred if `with_blur` and the
+    /// transmission isn't complete
+    pub fn display(&self, with_blur: bool) -> RgbaImage {
+        let mut data = self.picture();
+        if with_blur && !self.send_stage.is_end() {
+            self.smooth(&mut data, self.blur_step(), &self.known_mask());
+        }
+
+        data
+    }
+
+    /// Blurs `picture`, leaving the pixels of `known` as they are unless
+    /// `blur_known`
+    fn smooth(&self, picture: &mut RgbaImage, step: u32, known: &[bool]) {
+        if self.blur_known {
+            self.blur_kernel.apply(picture, step);
+        } else {
+            self.blur_kernel.apply_around(picture, step, known);
+        }
+    }
+
+    /// Whether the pixel at (`x`, `y`) was received with its true value,
+    /// rather than estimated from its neighbors
+    pub fn is_known(&self, x: u32, y: u32) -> bool {
+        let channels = self.receiving_image.channels();
+        self.send_stage.is_end() || self.known[(y * self.receiving_image.width() + x) as usize] as usize == channels
+    }
+
+    /// [Decoder::is_known] of every pixel, in raster order
+    fn known_mask(&self) -> Vec<bool> {
+        let (width, height) = self.receiving_image.dimensions();
+        self.known_rect(Rect::whole(width, height))
+    }
+
+    /// [Decoder::is_known] of the pixels inside `rect`, in raster order
+    fn known_rect(&self, rect: Rect) -> Vec<bool> {
+        let channels = self.receiving_image.channels();
+        let complete = self.send_stage.is_end();
+        let width = self.receiving_image.width() as usize;
+        (rect.y as usize..(rect.y + rect.height) as usize).flat_map(|y| {
+            let start = y * width + rect.x as usize;
+            self.known[start..start + rect.width as usize].iter().map(move |&count| complete || count as usize == channels)
+        }).collect()
+    }
+
+    /// Rows between the pixels of the current stage, which an automatic
+    /// [BlurKernel] follows. 1 for the schemes that don't send pixels
+    fn blur_step(&self) -> u32 {
+        if self.send_stage.is_end() {
+            return 1
+        }
+        self.send_stage.grid()
+            .or_else(|| self.send_stage.chroma_grid())
+            .map_or(1, |grid| grid.block.1)
+    }
+
+    /// The picture before blurring, with the missing pixels reconstructed
+    fn picture(&self) -> RgbaImage {
+        let display = self.receiving_image.to_display(&self.tone_mapping);
+        reconstruct::reconstruct(&display, &self.lattices(), self.reconstruction)
+    }
+
+    /// Lattices of the pixels received exactly, finest first. Empty once
+    /// every pixel is known, and for the schemes that don't send pixels
+    fn lattices(&self) -> Vec<Lattice> {
+        if self.send_stage.is_end() || !self.send_stage.is_spatial() {
+            return Vec::new()
+        }
+        // Stages that only carry chroma come after every luma sample
+        let Some(grid) = self.send_stage.grid() else {
+            return Vec::new()
+        };
+        let (width, height) = self.receiving_image.dimensions();
+        let rows = match self.send_stage.color() {
+            // Pixels arrive in raster order, every row above the next one is complete
+            ColorTransform::None => grid.positions(width, height).nth(self.stage_progress).map_or(height, |(_, y)| y),
+            // Planes arrive one after the other, only whole stages count
+            ColorTransform::YCoCgR { .. } => 0,
+        };
+
+        let mut lattices = vec![Lattice { step: grid.block, rows }];
+        lattices.extend(grid.known.map(|step| Lattice { step, rows: height }));
+        lattices
+    }
+
+    /// Shows the picture again, after the way it is shown changed
+    pub fn redraw(&mut self) {
+        let (width, height) = self.receiving_image.dimensions();
+        self.dirty = Some(Rect::whole(width, height));
+    }
+
+    /// Part of the picture shown with [Decoder::blur] that changed since the
+    /// last call, if any. [Decoder::shown] is drawn again inside it, and only
+    /// there
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        let dirty = self.dirty.take()?;
+        let (width, height) = self.receiving_image.dimensions();
+        if self.view.shown.dimensions() != (width, height) {
+            self.view = View::new(width, height);
+        }
+        let lattices = self.lattices();
+        let reconstructed = dirty.grow(reconstruction_spread(&lattices, self.reconstruction), width, height);
+        let blurred = reconstructed.grow(self.blur_spread(), width, height);
+
+        self.receiving_image.draw_display(&self.tone_mapping, dirty, &mut self.view.display);
+        reconstruct::reconstruct_rect(&self.view.display, &lattices, self.reconstruction, reconstructed, &mut self.view.picture);
+        let area = if self.blur && !self.send_stage.is_end() {
+            // The blur reads as far around the pixels it changes
+            let read = blurred.grow(self.blur_spread(), width, height);
+            let mut area = imageops::crop_imm(&self.view.picture, read.x, read.y, read.width, read.height).to_image();
+            self.smooth(&mut area, self.blur_step(), &self.known_rect(read));
+            imageops::crop_imm(&area, blurred.x - read.x, blurred.y - read.y, blurred.width, blurred.height).to_image()
+        } else {
+            imageops::crop_imm(&self.view.picture, blurred.x, blurred.y, blurred.width, blurred.height).to_image()
+        };
+        imageops::replace(&mut self.view.shown, &area, blurred.x as i64, blurred.y as i64);
+
+        Some(blurred)
+    }
+
+    /// The picture as shown on screen with [Decoder::blur], up to date as of
+    /// the last [Decoder::take_dirty]
+    pub fn shown(&self) -> &RgbaImage {
+        &self.view.shown
+    }
+
+    /// How far from a pixel that changed the blur may change
+    fn blur_spread(&self) -> u32 {
+        if self.blur && !self.send_stage.is_end() {
+            self.blur_kernel.parameters(self.blur_step()).0
+        } else {
+            0
+        }
+    }
+}
+
+/// How far from a pixel that changed the picture reconstructed from
+/// `lattices` may change
+fn reconstruction_spread(lattices: &[Lattice], reconstruction: Reconstruction) -> u32 {
+    let nodes = match reconstruction {
+        Reconstruction::Nearest => 0,
+        Reconstruction::Bilinear | Reconstruction::EdgeDirected => 1,
+        Reconstruction::Bicubic => 2,
+    };
+    let step = lattices.iter().map(|lattice| lattice.step.0.max(lattice.step.1)).max().unwrap_or(0);
+
+    nodes * step
+}
+
+/// Places `channels` of the pixels of a stage, starting at its pixel number
+/// `first`, each one filling the whole block it stands for and counted in
+/// `known`. Returns the amount of pixels placed.
+fn place_pixels(image: &mut Raster, known: &mut [u8], grid: StageGrid, channels: &[usize], predictor: Predictor, first: usize, data: &[u8]) -> usize {
+    let (block_width, block_height) = grid.block;
+    let (width, height) = image.dimensions();
+    let layout = image.layout();
+    let max = layout.max_sample();
+    let positions = grid.positions(width, height).skip(first);
+    let mut placed = 0;
+    for ((x, y), values) in positions.zip(data.chunks_exact(channels.len() * layout.bytes_per_sample())) {
+        let mut pixel = [0; raster::MAX_CHANNELS];
+        match predictor {
+            Predictor::None => {
+                for (&c, value) in channels.iter().zip(layout.read_samples(values)) {
+                    pixel[c] = value;
+                }
+            },
+            // Blocks never cover the pixels the predictions read
+            Predictor::Hierarchical => {
+                let prediction = predict::predict(image, &grid, x, y);
+                for (&c, residual) in channels.iter().zip(layout.read_samples(values)) {
+                    pixel[c] = predict::from_residual(residual, prediction[c], max);
+                }
+            },
+        }
+        known[(y * width + x) as usize] += channels.len() as u8;
+        for block_y in y..(y + block_height).min(height) {
+            for block_x in x..(x + block_width).min(width) {
+                let target = image.pixel_mut(block_x, block_y);
+                for &c in channels {
+                    target[c] = pixel[c];
+                }
+            }
+        }
+        placed += 1;
+    }
+
+    placed
+}
+
+/// Rows of the blocks of the pixels `first` to `first + count` of `grid`
+fn rows_placed(grid: StageGrid, width: u32, height: u32, first: usize, count: usize) -> Option<Rect> {
+    let mut positions = grid.positions(width, height).skip(first).take(count);
+    let (_, top) = positions.next()?;
+    let last = positions.last().map_or(top, |(_, y)| y);
+    let bottom = (last + grid.block.1).min(height);
+
+    Some(Rect { x: 0, y: top, width, height: bottom - top })
+}
+
+/// Pictures of `receiving_image`, kept between calls to [Decoder::take_dirty]
+struct View {
+    /// In 8 bit sRGB
+    display: RgbaImage,
+    /// With the missing pixels reconstructed
+    picture: RgbaImage,
+    /// Blurred if [Decoder::blur], as on screen
+    shown: RgbaImage,
+}
+
+impl View {
+    fn new(width: u32, height: u32) -> View {
+        View {
+            display: RgbaImage::new(width, height),
+            picture: RgbaImage::new(width, height),
+            shown: RgbaImage::new(width, height),
+        }
+    }
+}
+
+/// Picture at the end of a stage
+struct StageFrame {
+    picture: RgbaImage,
+    /// Rows between the pixels of the stage after it, which an automatic
+    /// [BlurKernel] follows
+    step: u32,
+    /// Whether every pixel was known exactly
+    known: Vec<bool>,
+}
+
+/// Scheme specific state of the client during a transmission
+enum StageDecoder {
+    /// Pixels go straight into the image
+    Pixels,
+    /// Pixels go into an image in YCoCg, converted back after every update
+    YCoCg { working: Raster },
+    Dct(Box<DctDecoder>),
+    Wavelet(WaveletDecoder),
+    /// Bits go straight into the image
+    BitPlanes { bits_per_stage: u8 },
+}
+
+impl StageDecoder {
+    fn new(scheme: Scheme, color: ColorTransform, layout: ChannelLayout, width: u32, height: u32) -> StageDecoder {
+        match scheme {
+            Scheme::Blocks(_) | Scheme::Adam7 => match color {
+                ColorTransform::None => StageDecoder::Pixels,
+                ColorTransform::YCoCgR { .. } => StageDecoder::YCoCg { working: Raster::new(width, height, layout) },
+            },
+            Scheme::Dct { quality } => StageDecoder::Dct(Box::new(DctDecoder::new(width, height, layout, quality))),
+            Scheme::Wavelet { levels } => StageDecoder::Wavelet(WaveletDecoder::new(width, height, layout, levels)),
+            Scheme::BitPlanes { bits_per_stage } => StageDecoder::BitPlanes { bits_per_stage },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReceiveError {
+    Wire(WireError),
+    AlreadyComplete,
+    SizeMismatch { expected: (u32, u32), got: (u32, u32) },
+    LayoutMismatch(ChannelLayout),
+    TransferMismatch(Transfer),
+    UnsupportedPredictor(Predictor),
+    UnknownScheme(u8),
+    SchemeMismatch { expected: Scheme, got: Scheme },
+    UnknownColorTransform(u8),
+    UnsupportedColorTransform(ColorTransform),
+    ColorMismatch { expected: ColorTransform, got: ColorTransform },
+    UnexpectedStage { expected: u8, got: u8 },
+    PayloadLength { expected: usize, got: usize },
+}
+
+impl From<WireError> for ReceiveError {
+    fn from(value: WireError) -> Self {
+        ReceiveError::Wire(value)
+    }
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiveError::Wire(e) => write!(f, "malformed packet: {e}"),
+            ReceiveError::AlreadyComplete => write!(f, "the image is already complete"),
+            ReceiveError::SizeMismatch { expected, got } => write!(f, "image size mismatch: expected {}x{}, got {}x{}", expected.0, expected.1, got.0, got.1),
+            ReceiveError::LayoutMismatch(layout) => write!(f, "unexpected channel layout {layout:?}"),
+            ReceiveError::TransferMismatch(transfer) => write!(f, "unexpected transfer curve {transfer:?}"),
+            ReceiveError::UnsupportedPredictor(predictor) => write!(f, "predictor {predictor:?} can't be used with this scheme"),
+            ReceiveError::UnknownScheme(id) => write!(f, "unknown scheme {id}"),
+            ReceiveError::SchemeMismatch { expected, got } => write!(f, "scheme mismatch: expected {expected:?}, got {got:?}"),
+            ReceiveError::UnknownColorTransform(id) => write!(f, "unknown color transform {id}"),
+            ReceiveError::UnsupportedColorTransform(color) => write!(f, "color transform {color:?} needs integer color channels"),
+            ReceiveError::ColorMismatch { expected, got } => write!(f, "color transform mismatch: expected {expected:?}, got {got:?}"),
+            ReceiveError::UnexpectedStage { expected, got } => write!(f, "unexpected stage: expected {expected}, got {got}"),
+            ReceiveError::PayloadLength { expected, got } => write!(f, "payload length mismatch: expected {expected} bytes, got {got}"),
+        }
+    }
+}
+
+impl std::error::Error for ReceiveError {}
@@ -0,0 +1,354 @@
+//! Sending side of a transmission: splits an image into stages and frames
+//! each of them as a [wire](crate::wire) packet.
+use std::{fmt, fs::File, io::{self, BufReader}, path::Path};
+
+use image::{codecs::jpeg::JpegDecoder, DynamicImage, ImageDecoder, RgbaImage};
+
+use crate::adam7::{self, ImportedPng};
+use crate::bitplane;
+use crate::color;
+use crate::dct::{self, DctCoefficients};
+use crate::entropy;
+use crate::predict;
+use crate::raster::{Raster, ALL_CHANNELS};
+use crate::scheme::{self, ColorTransform, Scheme, SendStage, StageGrid};
+use crate::tonemap::ToneMapping;
+use crate::transfer::Transfer;
+use crate::wavelet::WaveletCoefficients;
+use crate::wire::{self, ChannelLayout, Coding, PacketHeader, Predictor};
+
+/// Sends an image, one stage per call to [Encoder::send]. The settings only
+/// apply to the transmissions that start after they change.
+pub struct Encoder {
+    /// Kept with the channels, depth and transfer curve it was loaded with
+    sending_image: Raster,
+    /// Payload of every Adam7 pass, when the image comes from an interlaced PNG
+    png_passes: Option<Vec<Vec<u8>>>,
+    /// Coefficients of the last DCT transmission, kept until the quality changes
+    dct: Option<DctCoefficients>,
+    /// Coefficients of the last wavelet transmission, kept until the levels change
+    wavelet: Option<WaveletCoefficients>,
+    /// `sending_image` in YCoCg, once a transmission has needed it
+    ycocg: Option<Raster>,
+    /// Color transform of the next transmissions that send pixels
+    color: ColorTransform,
+    send_stage: SendStage,
+    /// Whether payloads are arithmetic coded
+    pub entropy_coding: bool,
+    /// Whether the schemes that send pixels send residuals instead, except
+    /// for the passes of an interlaced PNG
+    pub residual_prediction: bool,
+    /// Size of the last payload sent, as (bytes sent, bytes before coding)
+    last_payload: Option<(usize, usize)>,
+}
+
+impl Encoder {
+    pub fn new(image: Raster) -> Encoder {
+        Encoder::with_passes(image, None)
+    }
+
+    /// Sends the image at `path`. Interlaced PNGs stream their Adam7 passes
+    /// as they were read from the file, see [Encoder::has_png_passes]
+    pub fn open(path: impl AsRef<Path>) -> Result<Encoder, LoadError> {
+        let ImportedPng { image, passes } = load_image(path)?;
+        Ok(Encoder::with_passes(image, passes))
+    }
+
+    fn with_passes(sending_image: Raster, png_passes: Option<Vec<Vec<u8>>>) -> Encoder {
+        Encoder {
+            sending_image,
+            png_passes,
+            dct: None,
+            wavelet: None,
+            ycocg: None,
+            color: ColorTransform::default(),
+            send_stage: SendStage::init(Scheme::default(), ColorTransform::default()),
+            entropy_coding: true,
+            residual_prediction: true,
+            last_payload: None,
+        }
+    }
+
+    /// Returns the next stage, framed as a [wire] packet, or `None` once the
+    /// transmission [is complete](Self::is_complete)
+    pub fn send(&mut self) -> Option<Vec<u8>> {
+        if self.send_stage.is_end() {
+            return None
+        }
+        let stage = self.send_stage.index();
+        let height = self.sending_image.height();
+        let width = self.sending_image.width();
+
+        let predictor = if self.residual_prediction && self.send_stage.is_spatial() && !self.sends_png_passes(self.send_stage.scheme()) {
+            Predictor::Hierarchical
+        } else {
+            Predictor::None
+        };
+        let layout = self.sending_image.layout();
+        let v = match self.send_stage.color() {
+            ColorTransform::YCoCgR { .. } => {
+                let working = self.ycocg.get_or_insert_with(|| color::to_ycocg(&self.sending_image));
+                let mut v = Vec::new();
+                for (grid, channel) in scheme::ycocg_planes(&self.send_stage, layout) {
+                    v.extend(spatial_payload(working, &grid, &[channel], predictor));
+                }
+                v
+            },
+            ColorTransform::None => match self.send_stage.grid() {
+                Some(grid) if predictor == Predictor::Hierarchical => {
+                    spatial_payload(&self.sending_image, &grid, &ALL_CHANNELS[..layout.channels()], predictor)
+                },
+                _ => self.absolute_payload(),
+            },
+        };
+        let raw_len = v.len();
+        let (coding, v) = if self.entropy_coding {
+            (Coding::Arithmetic, entropy::encode(&v, scheme::stage_unit(&self.send_stage, layout)))
+        } else {
+            (Coding::Raw, v)
+        };
+        self.last_payload = Some((v.len(), raw_len));
+
+        let (scheme_id, scheme_param) = self.send_stage.scheme().to_wire();
+        let (color, color_param) = self.send_stage.color().to_wire();
+        let stage_count = self.send_stage.stages();
+        self.send_stage.next().unwrap();
+        Some(wire::encode(PacketHeader {
+            width,
+            height,
+            layout,
+            scheme: scheme_id,
+            scheme_param,
+            stage_count,
+            stage,
+            coding,
+            predictor,
+            color,
+            color_param,
+            transfer: self.sending_image.transfer().to_wire(),
+            payload_len: 0,
+            raw_len: raw_len as u32,
+        }, &v))
+    }
+
+    /// Payload of the next stage, with the values as they are
+    fn absolute_payload(&mut self) -> Vec<u8> {
+        let stage = self.send_stage.index();
+        let channels = &ALL_CHANNELS[..self.sending_image.channels()];
+
+        match (self.send_stage.scheme(), &self.png_passes) {
+            // Straight from the file
+            (Scheme::Adam7, Some(passes)) => passes[stage as usize].clone(),
+            (Scheme::Dct { quality }, _) => {
+                let cached = matches!(&self.dct, Some(dct) if dct.quality == quality);
+                if !cached {
+                    self.dct = Some(DctCoefficients::new(&self.sending_image, quality));
+                }
+                self.dct.as_ref().unwrap().encode_scan(&dct::SCANS[stage as usize])
+            },
+            (Scheme::Wavelet { levels }, _) => {
+                let cached = matches!(&self.wavelet, Some(wavelet) if wavelet.levels == levels);
+                if !cached {
+                    self.wavelet = Some(WaveletCoefficients::new(&self.sending_image, levels));
+                }
+                self.wavelet.as_ref().unwrap().encode_stage(stage)
+            },
+            (Scheme::BitPlanes { bits_per_stage }, _) => bitplane::encode_stage(&self.sending_image, bits_per_stage, stage),
+            _ => {
+                let grid = self.send_stage.grid().unwrap();
+                spatial_payload(&self.sending_image, &grid, channels, Predictor::None)
+            },
+        }
+    }
+
+    /// Starts the transmission over
+    pub fn clear(&mut self) {
+        self.send_stage = SendStage::init(self.send_stage.scheme(), self.stage_color(self.send_stage.scheme()))
+    }
+
+    /// Sends `image` from now on, restarting the transmission
+    pub fn set_image(&mut self, image: Raster) {
+        self.replace_image(image, None)
+    }
+
+    /// Sends the image at `path` from now on, restarting the transmission.
+    /// On failure, nothing changes
+    pub fn open_image(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let ImportedPng { image, passes } = load_image(path)?;
+        self.replace_image(image, passes);
+        Ok(())
+    }
+
+    fn replace_image(&mut self, image: Raster, png_passes: Option<Vec<Vec<u8>>>) {
+        self.sending_image = image;
+        self.png_passes = png_passes;
+        self.dct = None;
+        self.wavelet = None;
+        self.ycocg = None;
+        self.last_payload = None;
+        self.clear();
+    }
+
+    /// Width and height of the image, in pixels
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.sending_image.dimensions()
+    }
+
+    /// Size of the last payload sent, as (bytes sent, bytes before coding)
+    pub fn last_payload(&self) -> Option<(usize, usize)> {
+        self.last_payload
+    }
+
+    /// Channels and depth of the image, as sent
+    pub fn layout(&self) -> ChannelLayout {
+        self.sending_image.layout()
+    }
+
+    pub fn image(&self) -> &Raster {
+        &self.sending_image
+    }
+
+    /// The image as shown on screen, float images tone mapped by default
+    pub fn display(&self) -> RgbaImage {
+        self.sending_image.to_display(&ToneMapping::default())
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.send_stage.scheme()
+    }
+
+    /// Changes the scheme of the next transmission
+    pub fn set_scheme(&mut self, scheme: Scheme) {
+        self.send_stage = SendStage::init(scheme, self.stage_color(scheme))
+    }
+
+    pub fn color(&self) -> ColorTransform {
+        self.color
+    }
+
+    /// Changes the color transform of the next transmission. Only the schemes
+    /// that send pixels use it
+    pub fn set_color(&mut self, color: ColorTransform) {
+        self.color = color;
+        self.send_stage = SendStage::init(self.send_stage.scheme(), self.stage_color(self.send_stage.scheme()))
+    }
+
+    /// Grayscale and float images have no color to transform, and stored
+    /// passes are in the colors of the file
+    fn stage_color(&self, scheme: Scheme) -> ColorTransform {
+        if color::supports(self.sending_image.layout()) && !self.sends_png_passes(scheme) {
+            self.color
+        } else {
+            ColorTransform::None
+        }
+    }
+
+    fn sends_png_passes(&self, scheme: Scheme) -> bool {
+        scheme == Scheme::Adam7 && self.png_passes.is_some()
+    }
+
+    pub fn stage(&self) -> u8 {
+        self.send_stage.index()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.send_stage.is_end()
+    }
+
+    /// Whether the image came with Adam7 passes. The [Scheme::Adam7] streams
+    /// them as they were read from the file, without residuals or a color
+    /// transform
+    pub fn has_png_passes(&self) -> bool {
+        self.png_passes.is_some()
+    }
+}
+
+/// Opens the image at `path`. The passes of interlaced PNGs are kept too.
+fn load_image(path: impl AsRef<Path>) -> Result<ImportedPng, LoadError> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_ascii_lowercase();
+    let (image, passes) = match extension.as_str() {
+        "png" => {
+            let png = adam7::import_png(path)?;
+            (png.image, png.passes)
+        },
+        "jpg" | "jpeg" => {
+            // The ICC profile is only reachable through the decoder
+            let mut decoder = JpegDecoder::new(BufReader::new(File::open(path)?))?;
+            let transfer = decoder.icc_profile().as_deref().and_then(Transfer::from_icc);
+            let mut image = Raster::from_image(DynamicImage::from_decoder(decoder)?);
+            image.set_transfer(transfer.unwrap_or_default());
+            (image, None)
+        },
+        _ => (Raster::from_image(image::open(path)?), None),
+    };
+    if image.width() == 0 || image.height() == 0 {
+        return Err(LoadError::Empty)
+    }
+
+    Ok(ImportedPng {
+        image,
+        passes,
+    })
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Png(png::DecodingError),
+    Image(image::ImageError),
+    Empty,
+}
+
+impl From<png::DecodingError> for LoadError {
+    fn from(value: png::DecodingError) -> Self {
+        LoadError::Png(value)
+    }
+}
+
+impl From<image::ImageError> for LoadError {
+    fn from(value: image::ImageError) -> Self {
+        LoadError::Image(value)
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(value: io::Error) -> Self {
+        LoadError::Image(value.into())
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Png(e) => write!(f, "{e}"),
+            LoadError::Image(e) => write!(f, "{e}"),
+            LoadError::Empty => write!(f, "the image has no pixels"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Pixels of a stage, only `channels` of each
+fn spatial_payload(image: &Raster, grid: &StageGrid, channels: &[usize], predictor: Predictor) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let layout = image.layout();
+    let max = layout.max_sample();
+    let mut v = Vec::with_capacity(grid.sample_count(width, height) * channels.len() * layout.bytes_per_sample());
+    for (x, y) in grid.positions(width, height) {
+        let pixel = image.pixel(x, y);
+        match predictor {
+            Predictor::None => for &c in channels {
+                layout.push_sample(&mut v, pixel[c]);
+            },
+            Predictor::Hierarchical => {
+                let prediction = predict::predict(image, grid, x, y);
+                for &c in channels {
+                    layout.push_sample(&mut v, predict::to_residual(pixel[c], prediction[c], max));
+                }
+            },
+        }
+    }
+
+    v
+}
@@ -0,0 +1,255 @@
+//! Adaptive binary arithmetic coding of stage payloads.
+//!
+//! Payloads are made of units (a pixel, a DCT block, a wavelet coefficient...)
+//! whose bytes always mean the same thing at the same offset, so every offset
+//! (lane) has its own models. Inside a lane, the model is chosen by the
+//! previous byte of that lane, which belongs to the previous unit: the left
+//! neighbor for pixels, the same coefficient of the previous block for DCT.
+//! Models start over with every stage.
+//!
+//! Bytes are coded MSB first through a binary tree of probabilities, with the
+//! range coder of LZMA.
+
+/// Probabilities are out of `1 << PROB_BITS`
+const PROB_BITS: u32 = 11;
+/// How fast probabilities adapt, higher is slower
+const ADAPT_SHIFT: u32 = 5;
+const TOP: u32 = 1 << 24;
+/// Models of each lane, chosen by the previous byte of the lane
+const CONTEXTS: usize = 16;
+/// Most input bytes that decoding a single byte may read
+const MAX_BYTES_PER_BYTE: usize = 8;
+
+struct Model {
+    unit: usize,
+    /// `CONTEXTS` trees of 256 probabilities per lane
+    probabilities: Vec<u16>,
+    /// Last byte coded in every lane
+    previous: Vec<u8>,
+    /// Bytes coded so far
+    position: usize,
+}
+
+impl Model {
+    fn new(unit: usize) -> Model {
+        Model {
+            unit,
+            probabilities: vec![1 << (PROB_BITS - 1); unit * CONTEXTS * 256],
+            previous: vec![0; unit],
+            position: 0,
+        }
+    }
+
+    /// Start of the tree of the next byte
+    fn tree(&self) -> usize {
+        let lane = self.position % self.unit;
+        (lane * CONTEXTS + context(self.previous[lane])) * 256
+    }
+
+    fn push(&mut self, byte: u8) {
+        let lane = self.position % self.unit;
+        self.previous[lane] = byte;
+        self.position += 1;
+    }
+}
+
+/// Small values get a context each, bigger ones are grouped
+fn context(byte: u8) -> usize {
+    match byte {
+        0..=7 => byte as usize,
+        _ => 8 + (byte >> 5) as usize,
+    }
+}
+
+fn adapt(probability: &mut u16, bit: bool) {
+    if bit {
+        *probability -= *probability >> ADAPT_SHIFT;
+    } else {
+        *probability += ((1 << PROB_BITS) - *probability) >> ADAPT_SHIFT;
+    }
+}
+
+/// Codes a stage payload made of `unit` byte units
+pub fn encode(payload: &[u8], unit: usize) -> Vec<u8> {
+    let mut model = Model::new(unit);
+    let mut encoder = RangeEncoder::new();
+    for &byte in payload {
+        let tree = model.tree();
+        let mut node = 1;
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1 == 1;
+            encoder.encode(&mut model.probabilities[tree + node], bit);
+            node = node * 2 + bit as usize;
+        }
+        model.push(byte);
+    }
+
+    encoder.finish()
+}
+
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    /// Byte held back in case a carry propagates into it
+    cache: u8,
+    /// Bytes held back: the cache plus any 0xFF after it
+    cache_size: usize,
+    out: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new() -> RangeEncoder {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        }
+    }
+
+    fn encode(&mut self, probability: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * *probability as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        adapt(probability, bit);
+
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            for _ in 0..self.cache_size {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+            }
+            self.cache_size = 0;
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = ((self.low as u32) << 8) as u64;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+/// Decodes a payload as its coded bytes arrive
+pub struct EntropyDecoder {
+    model: Model,
+    input: Vec<u8>,
+    /// Next byte of `input` to read
+    read: usize,
+    /// Whether the whole coded payload is in `input`
+    input_complete: bool,
+    /// Whether the first bytes have been read into `code`
+    started: bool,
+    range: u32,
+    code: u32,
+}
+
+impl EntropyDecoder {
+    pub fn new(unit: usize) -> EntropyDecoder {
+        EntropyDecoder {
+            model: Model::new(unit),
+            input: Vec::new(),
+            read: 0,
+            input_complete: false,
+            started: false,
+            range: u32::MAX,
+            code: 0,
+        }
+    }
+
+    /// Adds coded bytes. `last` tells they are the end of the payload.
+    pub fn feed(&mut self, bytes: &[u8], last: bool) {
+        let read = self.read.min(self.input.len());
+        self.input.drain(..read);
+        self.read -= read;
+        self.input.extend_from_slice(bytes);
+        self.input_complete |= last;
+    }
+
+    /// Decodes as many whole units as the input allows, up to `max_units`
+    pub fn decode(&mut self, max_units: usize) -> Vec<u8> {
+        let unit = self.model.unit;
+        let mut v = Vec::new();
+        if !self.started {
+            if self.input.len() < 5 && !self.input_complete {
+                return v
+            }
+            for _ in 0..5 {
+                self.code = self.code << 8 | self.next_byte() as u32;
+            }
+            self.started = true;
+        }
+
+        for _ in 0..max_units {
+            // Without the end of the input, only decode what surely doesn't
+            // need bytes that haven't arrived
+            let available = self.input.len().saturating_sub(self.read);
+            if !self.input_complete && available < unit * MAX_BYTES_PER_BYTE {
+                break
+            }
+            for _ in 0..unit {
+                let byte = self.decode_byte();
+                v.push(byte);
+            }
+        }
+
+        v
+    }
+
+    fn decode_byte(&mut self) -> u8 {
+        let tree = self.model.tree();
+        let mut node = 1;
+        for _ in 0..8 {
+            let bit = self.decode_bit(tree + node);
+            node = node * 2 + bit as usize;
+        }
+        let byte = node as u8;
+        self.model.push(byte);
+
+        byte
+    }
+
+    fn decode_bit(&mut self, index: usize) -> bool {
+        let probability = &mut self.model.probabilities[index];
+        let bound = (self.range >> PROB_BITS) * *probability as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        adapt(probability, bit);
+
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.next_byte() as u32;
+        }
+
+        bit
+    }
+
+    /// Past the end of the input, the encoder would have written zeros
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.input.get(self.read).copied().unwrap_or(0);
+        self.read += 1;
+        byte
+    }
+}
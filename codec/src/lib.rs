@@ -0,0 +1,35 @@
+//! Progressive image transmission.
+//!
+//! An [Encoder] splits an image into stages according to a [Scheme] and
+//! frames each of them as a packet. A [Decoder] fed with those packets, in
+//! pieces of any size, shows what has arrived so far and ends up with the
+//! image the encoder sent.
+pub use self::blur::BlurKernel;
+pub use self::decoder::{Decoder, ReceiveError};
+pub use self::encoder::{Encoder, LoadError};
+pub use self::metrics::{compare, Quality, StageMetrics};
+pub use self::raster::{Raster, Rect};
+pub use self::reconstruct::Reconstruction;
+pub use self::scheme::{ColorTransform, Ladder, Scheme};
+pub use self::sequence::SequenceFormat;
+pub use self::tonemap::{ToneMapping, ToneOperator};
+pub use self::transfer::Transfer;
+pub use self::wire::{ChannelLayout, PROTOCOL_VERSION};
+mod adam7;
+mod bitplane;
+mod blur;
+mod color;
+mod dct;
+mod decoder;
+mod encoder;
+mod entropy;
+mod metrics;
+mod predict;
+mod raster;
+mod reconstruct;
+mod scheme;
+mod sequence;
+mod tonemap;
+mod transfer;
+mod wavelet;
+mod wire;
@@ -0,0 +1,100 @@
+//! Objective quality of the pictures a client shows.
+//!
+//! Pictures are compared as shown on screen: 8 bit sRGB, with float images
+//! tone mapped. SSIM is measured on the luma of 8x8 windows placed every 4
+//! pixels, and averaged.
+use image::RgbaImage;
+use rayon::prelude::*;
+
+const WINDOW: u32 = 8;
+const WINDOW_STEP: u32 = 4;
+const PEAK: f64 = 255.0;
+const C1: f64 = (0.01 * PEAK) * (0.01 * PEAK);
+const C2: f64 = (0.03 * PEAK) * (0.03 * PEAK);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Mean squared error of every channel, alpha included
+    pub mse: f64,
+    /// In dB. Infinite for identical pictures
+    pub psnr: f64,
+    /// Between -1 and 1, 1 for identical pictures
+    pub ssim: f64,
+}
+
+/// Quality of the picture at the end of a stage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StageMetrics {
+    pub stage: u8,
+    /// Bytes of the packet stream received until the stage was complete
+    pub bytes_received: usize,
+    pub raw: Quality,
+    /// Of the picture as blurred on screen
+    pub blurred: Quality,
+}
+
+/// Compares `image` to `reference`, which must have the same dimensions
+pub fn compare(reference: &RgbaImage, image: &RgbaImage) -> Quality {
+    assert_eq!(reference.dimensions(), image.dimensions());
+    let squared_error: u64 = reference.as_raw().par_iter().zip(image.as_raw().par_iter())
+        .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+        .sum();
+    let mse = squared_error as f64 / reference.as_raw().len().max(1) as f64;
+    let psnr = if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (PEAK * PEAK / mse).log10()
+    };
+
+    Quality {
+        mse,
+        psnr,
+        ssim: ssim(reference, image),
+    }
+}
+
+fn ssim(reference: &RgbaImage, image: &RgbaImage) -> f64 {
+    let (width, height) = reference.dimensions();
+    let reference = luma(reference);
+    let image = luma(image);
+    // Pictures smaller than a window are a single window
+    let window_w = WINDOW.min(width);
+    let window_h = WINDOW.min(height);
+    let xs: Vec<u32> = (0..=width - window_w).step_by(WINDOW_STEP as usize).collect();
+    let ys: Vec<u32> = (0..=height - window_h).step_by(WINDOW_STEP as usize).collect();
+
+    let total: f64 = ys.par_iter().map(|&y0| {
+        xs.iter().map(|&x0| {
+            let index = |x: u32, y: u32| (y * width + x) as usize;
+            let samples = (y0..y0 + window_h).flat_map(|y| (x0..x0 + window_w).map(move |x| (x, y)));
+            let n = (window_w * window_h) as f64;
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for (x, y) in samples {
+                let a = reference[index(x, y)];
+                let b = image[index(x, y)];
+                sum_a += a;
+                sum_b += b;
+                sum_aa += a * a;
+                sum_bb += b * b;
+                sum_ab += a * b;
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2))
+        }).sum::<f64>()
+    }).sum();
+
+    total / (xs.len() * ys.len()) as f64
+}
+
+/// Rec. 601 luma of every pixel
+fn luma(image: &RgbaImage) -> Vec<f64> {
+    image.pixels().map(|pixel| {
+        let [r, g, b, _] = pixel.0.map(|c| c as f64);
+        0.299 * r + 0.587 * g + 0.114 * b
+    }).collect()
+}
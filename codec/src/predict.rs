@@ -0,0 +1,81 @@
+//! Residual prediction for the schemes that send pixels.
+//!
+//! Before a stage, the client knows every pixel of a lattice that starts at
+//! (0, 0): the pixels of the previous stages. Each new pixel is predicted by
+//! interpolating bilinearly between the 4 lattice pixels around it, and only
+//! the difference is sent. The first stage has no lattice, so its pixels are
+//! predicted from the previous pixel of the stage on the same row (or column).
+//!
+//! Differences are taken per channel modulo the range of the samples and
+//! zigzag mapped (0, -1, 1, -2...) so small residuals are small values.
+use super::raster::{Raster, MAX_CHANNELS};
+use super::scheme::StageGrid;
+
+/// Predicted value of the pixel at (`x`, `y`) of `grid`. `image` must hold
+/// the exact values of every pixel the prediction reads, in the channels
+/// that are used. Only the first channels of the result are meaningful.
+pub fn predict(image: &Raster, grid: &StageGrid, x: u32, y: u32) -> [u32; MAX_CHANNELS] {
+    let (width, height) = image.dimensions();
+    let copy = |pixel: &[u32]| {
+        let mut prediction = [0; MAX_CHANNELS];
+        prediction[..pixel.len()].copy_from_slice(pixel);
+        prediction
+    };
+    let Some((step_x, step_y)) = grid.known else {
+        if x >= grid.origin.0 + grid.step.0 {
+            return copy(image.pixel(x - grid.step.0, y))
+        } else if y >= grid.origin.1 + grid.step.1 {
+            return copy(image.pixel(x, y - grid.step.1))
+        } else {
+            return [0; MAX_CHANNELS]
+        }
+    };
+
+    let x0 = x / step_x * step_x;
+    let y0 = y / step_y * step_y;
+    // Past the edge, the lattice pixel before it is used
+    let x1 = if x0 + step_x < width { x0 + step_x } else { x0 };
+    let y1 = if y0 + step_y < height { y0 + step_y } else { y0 };
+    let dx = x - x0;
+    let dy = y - y0;
+    let weights = [
+        (step_x - dx) * (step_y - dy),
+        dx * (step_y - dy),
+        (step_x - dx) * dy,
+        dx * dy,
+    ];
+    let corners = [
+        image.pixel(x0, y0),
+        image.pixel(x1, y0),
+        image.pixel(x0, y1),
+        image.pixel(x1, y1),
+    ];
+
+    let total = step_x as u64 * step_y as u64;
+    let mut prediction = [0; MAX_CHANNELS];
+    for (c, value) in prediction.iter_mut().enumerate().take(image.channels()) {
+        let sum: u64 = corners.iter().zip(weights).map(|(corner, weight)| corner[c] as u64 * weight as u64).sum();
+        *value = ((sum + total / 2) / total) as u32;
+    }
+
+    prediction
+}
+
+/// `max` is the highest value of a sample, see [super::wire::ChannelLayout::max_sample]
+pub fn to_residual(value: u32, prediction: u32, max: u32) -> u32 {
+    let difference = value.wrapping_sub(prediction) & max;
+    if difference <= max >> 1 {
+        difference << 1
+    } else {
+        (max - difference) << 1 | 1
+    }
+}
+
+pub fn from_residual(residual: u32, prediction: u32, max: u32) -> u32 {
+    let difference = if residual & 1 == 0 {
+        residual >> 1
+    } else {
+        max - (residual >> 1)
+    };
+    prediction.wrapping_add(difference) & max
+}
@@ -0,0 +1,230 @@
+//! Images with any of the channel layouts of the wire.
+use image::{DynamicImage, RgbaImage};
+
+use super::tonemap::ToneMapping;
+use super::transfer::Transfer;
+use super::wire::ChannelLayout;
+
+/// Most channels a pixel may have
+pub const MAX_CHANNELS: usize = 4;
+
+/// Every channel of a pixel, in the order they are sent
+pub const ALL_CHANNELS: [usize; MAX_CHANNELS] = [0, 1, 2, 3];
+
+/// Pixels in raster order. Samples are stored as `u32` whatever their depth,
+/// float samples as the bits of an `f32`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Raster {
+    width: u32,
+    height: u32,
+    layout: ChannelLayout,
+    transfer: Transfer,
+    samples: Vec<u32>,
+}
+
+impl Raster {
+    /// sRGB image with every sample set to 0
+    pub fn new(width: u32, height: u32, layout: ChannelLayout) -> Raster {
+        Raster {
+            width,
+            height,
+            layout,
+            transfer: Transfer::Srgb,
+            samples: vec![0; width as usize * height as usize * layout.channels()],
+        }
+    }
+
+    /// Keeps the channels and depth of `image`, taken as sRGB
+    pub fn from_image(image: DynamicImage) -> Raster {
+        let (width, height) = (image.width(), image.height());
+        let (layout, samples) = match image {
+            DynamicImage::ImageLuma8(image) => (ChannelLayout::Luma8, widen(image.into_raw())),
+            DynamicImage::ImageLumaA8(image) => (ChannelLayout::LumaA8, widen(image.into_raw())),
+            DynamicImage::ImageRgb8(image) => (ChannelLayout::Rgb8, widen(image.into_raw())),
+            DynamicImage::ImageRgba8(image) => (ChannelLayout::Rgba8, widen(image.into_raw())),
+            DynamicImage::ImageLuma16(image) => (ChannelLayout::Luma16, widen(image.into_raw())),
+            DynamicImage::ImageLumaA16(image) => (ChannelLayout::LumaA16, widen(image.into_raw())),
+            DynamicImage::ImageRgb16(image) => (ChannelLayout::Rgb16, widen(image.into_raw())),
+            DynamicImage::ImageRgba16(image) => (ChannelLayout::Rgba16, widen(image.into_raw())),
+            DynamicImage::ImageRgb32F(image) => (ChannelLayout::RgbF32, float_bits(image.into_raw())),
+            DynamicImage::ImageRgba32F(image) => (ChannelLayout::RgbaF32, float_bits(image.into_raw())),
+            image => (ChannelLayout::Rgba16, widen(image.into_rgba16().into_raw())),
+        };
+
+        Raster {
+            width,
+            height,
+            layout,
+            transfer: Transfer::Srgb,
+            samples,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    pub fn channels(&self) -> usize {
+        self.layout.channels()
+    }
+
+    pub fn transfer(&self) -> Transfer {
+        self.transfer
+    }
+
+    pub fn set_transfer(&mut self, transfer: Transfer) {
+        self.transfer = transfer
+    }
+
+    /// Every sample of every pixel, in raster order
+    pub fn samples(&self) -> &[u32] {
+        &self.samples
+    }
+
+    pub fn samples_mut(&mut self) -> &mut [u32] {
+        &mut self.samples
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &[u32] {
+        let start = self.index(x, y);
+        &self.samples[start..start + self.channels()]
+    }
+
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut [u32] {
+        let start = self.index(x, y);
+        let channels = self.channels();
+        &mut self.samples[start..start + channels]
+    }
+
+    pub fn pixels(&self) -> impl Iterator<Item = &[u32]> {
+        self.samples.chunks_exact(self.channels())
+    }
+
+    pub fn pixels_mut(&mut self) -> impl Iterator<Item = &mut [u32]> {
+        let channels = self.channels();
+        self.samples.chunks_exact_mut(channels)
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels()
+    }
+
+    /// 8 bit sRGB pixels, with straight alpha. Float images go through
+    /// `tone_mapping`, the others through their transfer curve
+    pub fn to_display(&self, tone_mapping: &ToneMapping) -> RgbaImage {
+        let mut image = RgbaImage::new(self.width, self.height);
+        self.draw_display(tone_mapping, Rect::whole(self.width, self.height), &mut image);
+
+        image
+    }
+
+    /// Converts the pixels inside `rect` like [Raster::to_display] does, into
+    /// the same place of `image`, which has the dimensions of the raster
+    pub(crate) fn draw_display(&self, tone_mapping: &ToneMapping, rect: Rect, image: &mut RgbaImage) {
+        let layout = self.layout;
+        let (color_table, alpha_table) = if layout.is_float() {
+            (Vec::new(), Vec::new())
+        } else {
+            // Alpha has no transfer curve, only its depth changes
+            (self.transfer.display_table(layout.max_sample()), Transfer::Srgb.display_table(layout.max_sample()))
+        };
+        let color = |sample: u32| if layout.is_float() {
+            tone_mapping.map(f32::from_bits(sample))
+        } else {
+            color_table[sample as usize]
+        };
+        let alpha = |sample: u32| if layout.is_float() {
+            (f32::from_bits(sample).clamp(0.0, 1.0) * 255.0).round() as u8
+        } else {
+            alpha_table[sample as usize]
+        };
+
+        let channels = self.channels();
+        let width = self.width as usize;
+        let columns = rect.x as usize..(rect.x + rect.width) as usize;
+        for y in rect.y as usize..(rect.y + rect.height) as usize {
+            let row = y * width + columns.start..y * width + columns.end;
+            let samples = &self.samples[row.start * channels..row.end * channels];
+            for (out, pixel) in image.as_mut()[row.start * 4..row.end * 4].chunks_exact_mut(4).zip(samples.chunks_exact(channels)) {
+                let [r, g, b] = match layout.rgb_channels() {
+                    Some(channels) => channels.map(|c| color(pixel[c])),
+                    None => [color(pixel[0]); 3],
+                };
+                let a = layout.alpha_channel().map_or(255, |c| alpha(pixel[c]));
+                out.copy_from_slice(&[r, g, b, a]);
+            }
+        }
+    }
+
+    /// Undoes [Raster::to_display], as an 8 bit sRGB image
+    pub fn from_display(image: &RgbaImage) -> Raster {
+        Raster {
+            width: image.width(),
+            height: image.height(),
+            layout: ChannelLayout::Rgba8,
+            transfer: Transfer::Srgb,
+            samples: widen(image.as_raw().clone()),
+        }
+    }
+}
+
+/// Area of an image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// All of a `width` x `height` image
+    pub fn whole(width: u32, height: u32) -> Rect {
+        Rect { x: 0, y: 0, width, height }
+    }
+
+    /// Smallest rectangle holding both
+    pub fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    /// Grown by `margin` on every side, without leaving a `width` x `height`
+    /// image
+    pub fn grow(self, margin: u32, width: u32, height: u32) -> Rect {
+        let x = self.x.saturating_sub(margin);
+        let y = self.y.saturating_sub(margin);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).saturating_add(margin).min(width) - x,
+            height: (self.y + self.height).saturating_add(margin).min(height) - y,
+        }
+    }
+}
+
+fn widen<T: Into<u32>>(samples: Vec<T>) -> Vec<u32> {
+    samples.into_iter().map(Into::into).collect()
+}
+
+fn float_bits(samples: Vec<f32>) -> Vec<u32> {
+    samples.into_iter().map(f32::to_bits).collect()
+}
@@ -0,0 +1,176 @@
+//! Estimates of the pixels that haven't arrived, from the ones that have.
+//!
+//! Spatial schemes send the nodes of ever finer lattices. Until a pixel
+//! arrives, the decoder shows the node at the corner of its block; the other
+//! modes interpolate between the nodes around it instead. They work on the
+//! picture as shown, 8 bit sRGB.
+use image::RgbaImage;
+use rayon::prelude::*;
+
+use super::raster::Rect;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reconstruction {
+    /// Every pixel shows the node at the corner of its block
+    #[default]
+    Nearest,
+    Bilinear,
+    /// Catmull-Rom splines through the 4x4 nodes around every pixel
+    Bicubic,
+    /// Splits every cell of the lattice in two triangles along the diagonal
+    /// whose corners differ the least, so edges aren't smeared across
+    EdgeDirected,
+}
+
+impl Reconstruction {
+    pub const ALL: [Reconstruction; 4] = [
+        Reconstruction::Nearest,
+        Reconstruction::Bilinear,
+        Reconstruction::Bicubic,
+        Reconstruction::EdgeDirected,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reconstruction::Nearest => "Nearest block",
+            Reconstruction::Bilinear => "Bilinear",
+            Reconstruction::Bicubic => "Bicubic",
+            Reconstruction::EdgeDirected => "Edge-directed",
+        }
+    }
+}
+
+/// Pixels known exactly: the ones at multiples of `step`, in the rows above
+/// `rows`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lattice {
+    pub step: (u32, u32),
+    pub rows: u32,
+}
+
+/// Fills every pixel of `image` from the nodes of the first of `lattices`
+/// known around it. `lattices` go from the finest to the coarsest, pixels
+/// none of them covers are kept.
+pub fn reconstruct(image: &RgbaImage, lattices: &[Lattice], mode: Reconstruction) -> RgbaImage {
+    if mode == Reconstruction::Nearest || lattices.is_empty() {
+        return image.clone()
+    }
+
+    let mut output = image.clone();
+    reconstruct_rect(image, lattices, mode, Rect::whole(image.width(), image.height()), &mut output);
+
+    output
+}
+
+/// Fills the pixels of `output` inside `rect` like [reconstruct] does. The
+/// nodes are read from all of `image`, which has the same dimensions
+pub fn reconstruct_rect(image: &RgbaImage, lattices: &[Lattice], mode: Reconstruction, rect: Rect, output: &mut RgbaImage) {
+    let (width, height) = image.dimensions();
+    let columns = rect.x as usize * 4..(rect.x + rect.width) as usize * 4;
+    let rows = output.par_chunks_exact_mut(width as usize * 4).enumerate().skip(rect.y as usize).take(rect.height as usize);
+    rows.for_each(|(y, row)| {
+        let y = y as u32;
+        let row = &mut row[columns.clone()];
+        let lattice = lattices.iter().find(|lattice| lattice.covers(y, height));
+        match lattice {
+            Some(lattice) if mode != Reconstruction::Nearest => {
+                for (x, pixel) in (rect.x..).zip(row.chunks_exact_mut(4)) {
+                    pixel.copy_from_slice(&lattice.interpolate(image, x, y, mode));
+                }
+            },
+            _ => {
+                let start = y as usize * width as usize * 4;
+                row.copy_from_slice(&image.as_raw()[start + columns.start..start + columns.end]);
+            },
+        }
+    });
+}
+
+impl Lattice {
+    /// Whether the nodes above and below row `y` are known
+    fn covers(&self, y: u32, height: u32) -> bool {
+        let last_row = (height - 1) / self.step.1;
+        let below = (y / self.step.1 + 1).min(last_row);
+        below * self.step.1 < self.rows
+    }
+
+    fn interpolate(&self, image: &RgbaImage, x: u32, y: u32, mode: Reconstruction) -> [u8; 4] {
+        let (width, height) = image.dimensions();
+        let (step_x, step_y) = self.step;
+        let last_column = ((width - 1) / step_x) as i64;
+        let last_row = (((height - 1) / step_y).min((self.rows - 1) / step_y)) as i64;
+        let node = |i: i64, j: i64| -> [f32; 4] {
+            let x = i.clamp(0, last_column) as u32 * step_x;
+            let y = j.clamp(0, last_row) as u32 * step_y;
+            image.get_pixel(x, y).0.map(|c| c as f32)
+        };
+        let (i, j) = ((x / step_x) as i64, (y / step_y) as i64);
+        let tx = (x % step_x) as f32 / step_x as f32;
+        let ty = (y % step_y) as f32 / step_y as f32;
+
+        let value = match mode {
+            Reconstruction::Nearest => node(i, j),
+            Reconstruction::Bilinear => {
+                let top = lerp(node(i, j), node(i + 1, j), tx);
+                let bottom = lerp(node(i, j + 1), node(i + 1, j + 1), tx);
+                lerp(top, bottom, ty)
+            },
+            Reconstruction::Bicubic => {
+                let rows = [-1, 0, 1, 2].map(|dj| {
+                    catmull_rom([-1, 0, 1, 2].map(|di| node(i + di, j + dj)), tx)
+                });
+                catmull_rom(rows, ty)
+            },
+            Reconstruction::EdgeDirected => {
+                let corners = [node(i, j), node(i + 1, j), node(i, j + 1), node(i + 1, j + 1)];
+                triangulated(corners, tx, ty)
+            },
+        };
+
+        value.map(|c| c.round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+fn lerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    std::array::from_fn(|c| a[c] + (b[c] - a[c]) * t)
+}
+
+fn catmull_rom(p: [[f32; 4]; 4], t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let weights = [
+        (-t3 + 2.0 * t2 - t) / 2.0,
+        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ];
+    std::array::from_fn(|c| (0..4).map(|k| p[k][c] * weights[k]).sum())
+}
+
+/// Interpolates inside the triangle of the cell the pixel falls in. The
+/// cell is split along the diagonal whose ends are clearly closer in luma,
+/// and bilinearly when neither is
+fn triangulated([top_left, top_right, bottom_left, bottom_right]: [[f32; 4]; 4], tx: f32, ty: f32) -> [f32; 4] {
+    let luma = |p: [f32; 4]| 0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2];
+    let falling = (luma(top_left) - luma(bottom_right)).abs();
+    let rising = (luma(top_right) - luma(bottom_left)).abs();
+    let blend = |points: [([f32; 4], f32); 3]| -> [f32; 4] {
+        std::array::from_fn(|c| points.iter().map(|(p, weight)| p[c] * weight).sum())
+    };
+
+    if falling * 2.0 < rising {
+        if tx >= ty {
+            blend([(top_left, 1.0 - tx), (top_right, tx - ty), (bottom_right, ty)])
+        } else {
+            blend([(top_left, 1.0 - ty), (bottom_left, ty - tx), (bottom_right, tx)])
+        }
+    } else if rising * 2.0 < falling {
+        if tx + ty <= 1.0 {
+            blend([(top_left, 1.0 - tx - ty), (top_right, tx), (bottom_left, ty)])
+        } else {
+            blend([(bottom_right, tx + ty - 1.0), (top_right, 1.0 - ty), (bottom_left, 1.0 - tx)])
+        }
+    } else {
+        lerp(lerp(top_left, top_right, tx), lerp(bottom_left, bottom_right, tx), ty)
+    }
+}
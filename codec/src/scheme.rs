@@ -0,0 +1,395 @@
+//! What is sent at every stage of a transmission.
+use super::{adam7, bitplane, color, dct, wavelet};
+use super::wire::ChannelLayout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// Square blocks that halve (or less) in size every stage
+    Blocks(Ladder),
+    /// The 7 passes of interlaced PNGs
+    Adam7,
+    /// DCT coefficients of 8x8 blocks, like progressive JPEG.
+    /// `quality` goes from 1 to 100
+    Dct { quality: u8 },
+    /// Subbands of the reversible 5/3 wavelet, one decomposition level per
+    /// stage after the first one. Lossless
+    Wavelet { levels: u8 },
+    /// Every pixel at once, `bits_per_stage` more significant bits at a time
+    BitPlanes { bits_per_stage: u8 },
+}
+
+impl Default for Scheme {
+    fn default() -> Self {
+        Scheme::Blocks(Ladder::default())
+    }
+}
+
+impl Scheme {
+    pub const MAX_WAVELET_LEVELS: u8 = wavelet::MAX_LEVELS;
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scheme::Blocks(_) => "Blocks",
+            Scheme::Adam7 => "Adam7",
+            Scheme::Dct { .. } => "DCT",
+            Scheme::Wavelet { .. } => "Wavelet",
+            Scheme::BitPlanes { .. } => "Bit planes",
+        }
+    }
+
+    pub fn stages(&self) -> u8 {
+        match self {
+            Scheme::Blocks(ladder) => ladder.stages,
+            Scheme::Adam7 => adam7::PASSES.len() as u8,
+            Scheme::Dct { .. } => dct::SCANS.len() as u8,
+            Scheme::Wavelet { levels } => levels + 1,
+            Scheme::BitPlanes { bits_per_stage } => bitplane::stages(*bits_per_stage),
+        }
+    }
+
+    /// (scheme id, scheme parameter) as sent on the wire
+    pub(super) fn to_wire(self) -> (u8, u8) {
+        match self {
+            Scheme::Blocks(ladder) => (0, ladder.coarsest),
+            Scheme::Adam7 => (1, 0),
+            Scheme::Dct { quality } => (2, quality),
+            Scheme::Wavelet { levels } => (3, levels),
+            Scheme::BitPlanes { bits_per_stage } => (4, bits_per_stage),
+        }
+    }
+
+    pub(super) fn from_wire(id: u8, param: u8, stages: u8) -> Option<Scheme> {
+        let scheme = match id {
+            0 => Scheme::Blocks(Ladder::from_wire(param, stages)?),
+            1 => Scheme::Adam7,
+            2 if (1..=100).contains(&param) => Scheme::Dct { quality: param },
+            3 if (1..=wavelet::MAX_LEVELS).contains(&param) => Scheme::Wavelet { levels: param },
+            4 if (1..=8).contains(&param) => Scheme::BitPlanes { bits_per_stage: param },
+            _ => return None,
+        };
+
+        (scheme.stages() == stages).then_some(scheme)
+    }
+
+    /// Pixels sent by a stage. `None` for schemes that don't send pixels
+    fn grid(&self, stage: u8) -> Option<StageGrid> {
+        let grid = match self {
+            Scheme::Blocks(ladder) => {
+                let step = 1 << ladder.block_log2(stage);
+                let exclude = stage.checked_sub(1).map(|previous| 1 << ladder.block_log2(previous));
+                StageGrid {
+                    origin: (0, 0),
+                    step: (step, step),
+                    exclude,
+                    block: (step, step),
+                    known: None,
+                }
+            },
+            Scheme::Adam7 => {
+                let pass = &adam7::PASSES[stage as usize];
+                StageGrid {
+                    origin: pass.origin,
+                    step: pass.step,
+                    exclude: None,
+                    block: pass.block,
+                    known: None,
+                }
+            },
+            Scheme::Dct { .. } | Scheme::Wavelet { .. } | Scheme::BitPlanes { .. } => return None,
+        };
+
+        // Every stage fills the gaps of the lattice of the previous one
+        let known = match stage.checked_sub(1) {
+            Some(previous) => Some(self.grid(previous)?.block),
+            None => None,
+        };
+
+        Some(StageGrid {
+            known,
+            ..grid
+        })
+    }
+}
+
+/// Block sizes of a transmission, from the coarsest one down to 1x1.
+/// Every block size is a power of two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ladder {
+    /// log2 of the coarsest block size
+    coarsest: u8,
+    stages: u8,
+}
+
+impl Default for Ladder {
+    fn default() -> Self {
+        Ladder::new(6, 7)
+    }
+}
+
+impl Ladder {
+    /// log2 of the biggest block size allowed
+    pub const MAX_COARSEST: u8 = 15;
+
+    /// `coarsest` is the log2 of the block size of the first stage. `stages`
+    /// is clamped so no two stages have the same block size.
+    pub fn new(coarsest: u8, stages: u8) -> Ladder {
+        let coarsest = coarsest.min(Ladder::MAX_COARSEST);
+        Ladder {
+            coarsest,
+            stages: stages.clamp(1, coarsest + 1),
+        }
+    }
+
+    /// Like [Ladder::new], but rejects the values instead of clamping them
+    fn from_wire(coarsest: u8, stages: u8) -> Option<Ladder> {
+        let ladder = Ladder::new(coarsest, stages);
+        if ladder.coarsest == coarsest && ladder.stages == stages {
+            Some(ladder)
+        } else {
+            None
+        }
+    }
+
+    /// Every block size from the smallest one whose first stage has at most
+    /// `first_stage_pixels` pixels down to 1x1.
+    pub fn for_image(width: u32, height: u32, first_stage_pixels: u64) -> Ladder {
+        let coarsest = (0..=Ladder::MAX_COARSEST).find(|log2| {
+            let block = 1 << log2;
+            width.div_ceil(block) as u64 * height.div_ceil(block) as u64 <= first_stage_pixels
+        }).unwrap_or(Ladder::MAX_COARSEST);
+
+        Ladder::new(coarsest, coarsest + 1)
+    }
+
+    pub fn coarsest(&self) -> u8 {
+        self.coarsest
+    }
+
+    pub fn stages(&self) -> u8 {
+        self.stages
+    }
+
+    pub fn block_sizes(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.stages).map(|stage| 1 << self.block_log2(stage))
+    }
+
+    /// log2 of the block size of the given stage. When there are fewer stages
+    /// than block sizes, the skipped sizes are spread evenly.
+    fn block_log2(&self, stage: u8) -> u32 {
+        if self.stages == 1 {
+            return 0
+        }
+        let gaps = self.stages as u32 - 1;
+        let remaining = gaps - stage as u32;
+
+        (self.coarsest as u32 * remaining + gaps / 2) / gaps
+    }
+}
+
+/// Pixels sent by a stage of a spatial scheme: a regular grid, minus the pixels of a coarser
+/// grid that were already sent.
+#[derive(Debug, Clone, Copy)]
+pub struct StageGrid {
+    /// First pixel of the grid
+    pub origin: (u32, u32),
+    /// Distance between two pixels of the grid
+    pub step: (u32, u32),
+    /// Step of a grid starting at (0, 0) whose pixels are skipped
+    exclude: Option<u32>,
+    /// Size of the area each pixel stands for until a later stage refines it
+    pub block: (u32, u32),
+    /// Step of the lattice starting at (0, 0) whose pixels were all sent by
+    /// the previous stages. `None` for the first stage
+    pub known: Option<(u32, u32)>,
+}
+
+impl StageGrid {
+    /// Pixels of the grid in the order they are sent
+    pub fn positions(&self, width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+        let grid = *self;
+        (grid.origin.1..height).step_by(grid.step.1 as usize).flat_map(move |y| {
+            (grid.origin.0..width).step_by(grid.step.0 as usize)
+                .filter(move |x| match grid.exclude {
+                    Some(exclude) => y % exclude != 0 || x % exclude != 0,
+                    None => true,
+                })
+                .map(move |x| (x, y))
+        })
+    }
+
+    /// Amount of pixels of the grid
+    pub fn sample_count(&self, width: u32, height: u32) -> usize {
+        let count = |origin: (u32, u32), step: (u32, u32)| {
+            width.saturating_sub(origin.0).div_ceil(step.0) as usize
+            * height.saturating_sub(origin.1).div_ceil(step.1) as usize
+        };
+        let excluded = match self.exclude {
+            Some(exclude) => count((0, 0), (exclude, exclude)),
+            None => 0,
+        };
+
+        count(self.origin, self.step) - excluded
+    }
+}
+
+/// Color space the pixel schemes send their samples in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorTransform {
+    /// The channels of the image as they are
+    #[default]
+    None,
+    /// Reversible YCoCg, with the chroma planes sent `chroma_lag` stages
+    /// after the luma plane. The transmission gets `chroma_lag` extra stages
+    /// that only carry chroma.
+    YCoCgR { chroma_lag: u8 },
+}
+
+impl ColorTransform {
+    /// Longest lag allowed
+    pub const MAX_CHROMA_LAG: u8 = 2;
+
+    /// Stages added after the ones of the scheme
+    pub fn extra_stages(&self) -> u8 {
+        match self {
+            ColorTransform::None => 0,
+            ColorTransform::YCoCgR { chroma_lag } => *chroma_lag,
+        }
+    }
+
+    /// (transform id, transform parameter) as sent on the wire
+    pub(super) fn to_wire(self) -> (u8, u8) {
+        match self {
+            ColorTransform::None => (0, 0),
+            ColorTransform::YCoCgR { chroma_lag } => (1, chroma_lag),
+        }
+    }
+
+    pub(super) fn from_wire(id: u8, param: u8) -> Option<ColorTransform> {
+        match id {
+            0 => Some(ColorTransform::None),
+            1 if param <= ColorTransform::MAX_CHROMA_LAG => Some(ColorTransform::YCoCgR { chroma_lag: param }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendStage {
+    scheme: Scheme,
+    /// Always [ColorTransform::None] for schemes that don't send pixels
+    color: ColorTransform,
+    /// Equal to the amount of stages once everything is sent
+    index: u8,
+}
+
+impl SendStage {
+    /// `color` is ignored by the schemes that don't send pixels
+    pub fn init(scheme: Scheme, color: ColorTransform) -> SendStage {
+        let color = match scheme {
+            Scheme::Blocks(_) | Scheme::Adam7 => color,
+            _ => ColorTransform::None,
+        };
+        SendStage {
+            scheme,
+            color,
+            index: 0,
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    pub fn color(&self) -> ColorTransform {
+        self.color
+    }
+
+    /// Stages of the whole transmission
+    pub fn stages(&self) -> u8 {
+        self.scheme.stages() + self.color.extra_stages()
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.index >= self.stages()
+    }
+
+    pub fn next(&mut self) -> Result<(), ()> {
+        if self.is_end() {
+            return Err(())
+        }
+        self.index += 1;
+
+        Ok(())
+    }
+
+    /// Identifier of the stage on the wire
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Pixels sent by the stage. With [ColorTransform::YCoCgR] only their
+    /// luma and alpha are sent. `None` for the schemes that don't send pixels
+    /// and for the stages that only carry chroma.
+    pub fn grid(&self) -> Option<StageGrid> {
+        assert!(!self.is_end());
+        if self.index >= self.scheme.stages() {
+            return None
+        }
+        self.scheme.grid(self.index)
+    }
+
+    /// Pixels whose chroma is sent by the stage, with [ColorTransform::YCoCgR]
+    pub fn chroma_grid(&self) -> Option<StageGrid> {
+        assert!(!self.is_end());
+        let ColorTransform::YCoCgR { chroma_lag } = self.color else {
+            return None
+        };
+        self.scheme.grid(self.index.checked_sub(chroma_lag)?)
+    }
+
+    /// Whether the stage sends pixels, whole or split in planes
+    pub fn is_spatial(&self) -> bool {
+        matches!(self.scheme, Scheme::Blocks(_) | Scheme::Adam7)
+    }
+}
+
+/// Smallest piece of the payload of `stage` that can be placed on its own
+pub fn stage_unit(stage: &SendStage, layout: ChannelLayout) -> usize {
+    match stage.scheme() {
+        Scheme::Dct { .. } => dct::SCANS[stage.index() as usize].block_bytes(layout),
+        Scheme::Wavelet { .. } => wavelet::unit_bytes(layout),
+        Scheme::BitPlanes { bits_per_stage } => bitplane::unit_bytes(layout, bits_per_stage, stage.index()),
+        Scheme::Blocks(_) | Scheme::Adam7 => match stage.color() {
+            // Every plane goes on its own
+            ColorTransform::YCoCgR { .. } => layout.bytes_per_sample(),
+            ColorTransform::None => layout.bytes_per_pixel(),
+        },
+    }
+}
+
+/// Amount of units in the payload of `stage`
+pub fn stage_units(stage: &SendStage, layout: ChannelLayout, width: u32, height: u32) -> usize {
+    match stage.scheme() {
+        Scheme::Dct { .. } => dct::block_count(width, height),
+        Scheme::Wavelet { levels } => wavelet::stage_len(width, height, levels, stage.index()),
+        Scheme::BitPlanes { .. } => bitplane::unit_count(width, height),
+        Scheme::Blocks(_) | Scheme::Adam7 => match stage.color() {
+            ColorTransform::YCoCgR { .. } => ycocg_planes(stage, layout).iter()
+                .map(|(grid, _)| grid.sample_count(width, height))
+                .sum(),
+            ColorTransform::None => stage.grid().unwrap().sample_count(width, height),
+        },
+    }
+}
+
+/// Planes sent by `stage` with [ColorTransform::YCoCgR], in order: luma
+/// (and alpha) of the pixels of the stage, then chroma of the pixels that
+/// lag behind. Each plane is a channel of the transformed image.
+pub fn ycocg_planes(stage: &SendStage, layout: ChannelLayout) -> Vec<(StageGrid, usize)> {
+    let luma = stage.grid().into_iter()
+        .flat_map(|grid| color::luma_channels(layout).iter().map(move |&channel| (grid, channel)));
+    let chroma = stage.chroma_grid().into_iter()
+        .flat_map(|grid| color::CHROMA.iter().map(move |&channel| (grid, channel)));
+
+    luma.chain(chroma).collect()
+}
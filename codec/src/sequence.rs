@@ -0,0 +1,81 @@
+//! Writing the stages a client went through as an animation or as numbered
+//! pictures.
+use std::{fs::File, io::{self, BufWriter}, path::{Path, PathBuf}, time::Duration};
+
+use image::{codecs::gif::{GifEncoder, Repeat}, Delay, Frame, RgbaImage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SequenceFormat {
+    #[default]
+    Gif,
+    Apng,
+    /// One PNG per stage, numbered after the name of the file
+    PngSeries,
+}
+
+impl SequenceFormat {
+    pub const ALL: [SequenceFormat; 3] = [SequenceFormat::Gif, SequenceFormat::Apng, SequenceFormat::PngSeries];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SequenceFormat::Gif => "Animated GIF",
+            SequenceFormat::Apng => "Animated PNG",
+            SequenceFormat::PngSeries => "Numbered PNGs",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SequenceFormat::Gif => "gif",
+            SequenceFormat::Apng | SequenceFormat::PngSeries => "png",
+        }
+    }
+}
+
+/// Writes `frames`, which must all have the same dimensions, showing each of
+/// them for `delay`. Animations loop forever
+pub fn save(frames: &[RgbaImage], format: SequenceFormat, delay: Duration, path: impl AsRef<Path>) -> io::Result<()> {
+    if frames.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no stages to save"))
+    }
+    let path = path.as_ref();
+    match format {
+        SequenceFormat::Gif => {
+            let mut encoder = GifEncoder::new_with_speed(BufWriter::new(File::create(path)?), 10);
+            encoder.set_repeat(Repeat::Infinite).map_err(io::Error::other)?;
+            let delay = Delay::from_saturating_duration(delay);
+            encoder.encode_frames(frames.iter().map(|frame| Frame::from_parts(frame.clone(), 0, 0, delay)))
+                .map_err(io::Error::other)
+        },
+        SequenceFormat::Apng => {
+            let (width, height) = frames[0].dimensions();
+            let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(frames.len() as u32, 0)?;
+            encoder.set_frame_delay(delay.as_millis().min(u16::MAX as u128) as u16, 1000)?;
+            let mut writer = encoder.write_header()?;
+            for frame in frames {
+                writer.write_image_data(frame.as_raw())?;
+            }
+            writer.finish()?;
+            Ok(())
+        },
+        SequenceFormat::PngSeries => {
+            for (i, frame) in frames.iter().enumerate() {
+                frame.save_with_format(numbered(path, i), image::ImageFormat::Png).map_err(io::Error::other)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+/// `path` with `_NN` appended to its name, keeping the extension
+fn numbered(path: &Path, i: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}_{i:02}.{}", extension.to_string_lossy()),
+        None => format!("{stem}_{i:02}"),
+    };
+    path.with_file_name(name)
+}
@@ -0,0 +1,52 @@
+//! Turns the linear values of float images into the 8 bit sRGB of the
+//! textures.
+use super::transfer::linear_to_srgb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneOperator {
+    /// Values above 1 are clipped
+    Clamp,
+    /// x / (1 + x)
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve
+    #[default]
+    AcesFit,
+}
+
+impl ToneOperator {
+    pub const ALL: [ToneOperator; 3] = [ToneOperator::Clamp, ToneOperator::Reinhard, ToneOperator::AcesFit];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneOperator::Clamp => "Clamp",
+            ToneOperator::Reinhard => "Reinhard",
+            ToneOperator::AcesFit => "ACES (fit)",
+        }
+    }
+
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            ToneOperator::Clamp => x,
+            ToneOperator::Reinhard => x / (1.0 + x),
+            ToneOperator::AcesFit => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ToneMapping {
+    /// In stops: every step doubles the values
+    pub exposure: f32,
+    pub operator: ToneOperator,
+}
+
+impl ToneMapping {
+    /// sRGB encoded value of a linear color channel
+    pub fn map(&self, linear: f32) -> u8 {
+        // NaN and negative values end up black
+        let exposed = (linear * self.exposure.exp2()).max(0.0);
+        let mapped = self.operator.apply(exposed).clamp(0.0, 1.0);
+
+        (linear_to_srgb(mapped) * 255.0).round() as u8
+    }
+}
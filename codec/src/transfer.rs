@@ -0,0 +1,180 @@
+//! Transfer curves: how the integer samples of an image encode light.
+//!
+//! Images keep the curve they were stored with and travel with it, so both
+//! ends show them the same way. Float images always hold linear light and
+//! don't use it. Of ICC profiles, only the curve of their red (or gray)
+//! channel is honored: the primaries are taken to be the ones of sRGB.
+
+/// Sent as the `gAMA` chunk of PNG does, with 0 standing for sRGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transfer {
+    /// The piecewise curve of sRGB
+    #[default]
+    Srgb,
+    /// Linear light raised to `gamma / 100000`
+    Gamma(u32),
+}
+
+impl Transfer {
+    pub fn to_wire(self) -> u32 {
+        match self {
+            Transfer::Srgb => 0,
+            Transfer::Gamma(gamma) => gamma,
+        }
+    }
+
+    pub fn from_wire(value: u32) -> Transfer {
+        match value {
+            0 => Transfer::Srgb,
+            gamma => Transfer::Gamma(gamma),
+        }
+    }
+
+    /// Curve stated by the chunks of a PNG file. `sRGB` wins over `iCCP`,
+    /// which wins over `gAMA`, as the PNG specification asks
+    pub fn from_png(info: &png::Info) -> Transfer {
+        if info.srgb.is_some() {
+            return Transfer::Srgb
+        }
+        if let Some(transfer) = info.icc_profile.as_deref().and_then(Transfer::from_icc) {
+            return transfer
+        }
+        match info.source_gamma {
+            Some(gamma) => Transfer::Gamma(gamma.into_scaled()),
+            None => Transfer::Srgb,
+        }
+    }
+
+    /// Curve of the red (or gray) channel of an ICC profile. Curves close to
+    /// the one of sRGB are taken as sRGB, others as the closest gamma
+    pub fn from_icc(profile: &[u8]) -> Option<Transfer> {
+        let curve = icc_curve(profile, b"rTRC").or_else(|| icc_curve(profile, b"kTRC"))?;
+        let is_srgb = (1..16).all(|i| {
+            let x = i as f32 / 16.0;
+            (curve.decode(x) - srgb_to_linear(x)).abs() < 0.001
+        });
+        if is_srgb {
+            return Some(Transfer::Srgb)
+        }
+
+        // Exponent that goes through the middle of the curve
+        let exponent = curve.decode(0.5).max(f32::MIN_POSITIVE).ln() / 0.5f32.ln();
+        if !exponent.is_finite() || exponent <= 0.0 {
+            return None
+        }
+        // 0 would stand for sRGB on the wire
+        Some(Transfer::Gamma(((100_000.0 / exponent).round() as u32).max(1)))
+    }
+
+    /// Linear light of an encoded value, both between 0 and 1
+    pub fn to_linear(self, encoded: f32) -> f32 {
+        match self {
+            Transfer::Srgb => srgb_to_linear(encoded),
+            Transfer::Gamma(gamma) => encoded.powf(100_000.0 / gamma as f32),
+        }
+    }
+
+    /// 8 bit sRGB value of every sample up to `max`
+    pub fn display_table(self, max: u32) -> Vec<u8> {
+        let max = max as u64;
+        (0..=max).map(|sample| match self {
+            // Already display ready, only the depth changes
+            Transfer::Srgb => ((sample * 255 + max / 2) / max) as u8,
+            Transfer::Gamma(_) => {
+                let linear = self.to_linear(sample as f32 / max as f32);
+                (linear_to_srgb(linear) * 255.0).round() as u8
+            },
+        }).collect()
+    }
+}
+
+pub fn srgb_to_linear(encoded: f32) -> f32 {
+    if encoded <= 0.040_45 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(linear: f32) -> f32 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Curve tags of ICC profiles: `curv` (a gamma or a table) and `para`
+enum IccCurve {
+    Gamma(f32),
+    Table(Vec<f32>),
+    /// Function type and its parameters g, a, b, c, d, e, f
+    Parametric(u16, [f32; 7]),
+}
+
+impl IccCurve {
+    fn decode(&self, x: f32) -> f32 {
+        match self {
+            IccCurve::Gamma(gamma) => x.powf(*gamma),
+            IccCurve::Table(table) => {
+                let position = x * (table.len() - 1) as f32;
+                let i = (position as usize).min(table.len() - 2);
+                let t = position - i as f32;
+                table[i] * (1.0 - t) + table[i + 1] * t
+            },
+            IccCurve::Parametric(kind, [g, a, b, c, d, e, f]) => match kind {
+                0 => x.powf(*g),
+                1 if x >= -b / a => (a * x + b).powf(*g),
+                1 => 0.0,
+                2 if x >= -b / a => (a * x + b).powf(*g) + c,
+                2 => *c,
+                3 if x >= *d => (a * x + b).powf(*g),
+                3 => c * x,
+                _ if x >= *d => (a * x + b).powf(*g) + e,
+                _ => c * x + f,
+            },
+        }
+    }
+}
+
+fn icc_curve(profile: &[u8], signature: &[u8; 4]) -> Option<IccCurve> {
+    let u16_at = |at: usize| Some(u16::from_be_bytes(profile.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_be_bytes(profile.get(at..at + 4)?.try_into().ok()?));
+    let s15_16_at = |at: usize| Some(u32_at(at)? as i32 as f32 / 65536.0);
+
+    // The tag table follows the 128 byte header
+    let tag_count = u32_at(128)? as usize;
+    let tag = (0..tag_count).map(|i| 132 + i * 12)
+        .take_while(|&at| at + 12 <= profile.len())
+        .find(|&at| profile.get(at..at + 4) == Some(signature))?;
+    let at = u32_at(tag + 4)? as usize;
+    match profile.get(at..at + 4)? {
+        b"curv" => match u32_at(at + 8)? {
+            0 => Some(IccCurve::Gamma(1.0)),
+            1 => Some(IccCurve::Gamma(u16_at(at + 12)? as f32 / 256.0)),
+            count => {
+                let table = (0..count as usize)
+                    .map(|i| Some(u16_at(at + 12 + i * 2)? as f32 / 65535.0))
+                    .collect::<Option<Vec<f32>>>()?;
+                Some(IccCurve::Table(table))
+            },
+        },
+        b"para" => {
+            let kind = u16_at(at + 8)?;
+            let count = match kind {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return None,
+            };
+            let mut parameters = [0.0; 7];
+            for (i, parameter) in parameters.iter_mut().take(count).enumerate() {
+                *parameter = s15_16_at(at + 12 + i * 4)?;
+            }
+            Some(IccCurve::Parametric(kind, parameters))
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,258 @@
+//! Lossless multi-resolution scheme using the reversible 5/3 lifting wavelet
+//! of JPEG 2000.
+//!
+//! Every channel is decomposed `levels` times. The coefficients are kept in
+//! the usual in-place layout: after each level the low-pass band (LL) sits in
+//! the top left corner, with the HL, LH and HH bands to its right, below it
+//! and diagonally from it. The first stage sends the last LL band, and each
+//! of the following stages sends the HL, LH and HH bands of one level, from
+//! the coarsest to the finest.
+//!
+//! Every coefficient is sent per channel, in the channel order of the image,
+//! as an integer twice as wide as the samples (`i16` for 8 bit samples), which
+//! always fits it. Float samples are transformed as the bits of their `f32`.
+use super::raster::Raster;
+use super::wire::ChannelLayout;
+
+/// Most decompositions a transmission may use
+pub const MAX_LEVELS: u8 = 16;
+
+/// Area of the coefficient layout, as (x0, y0, x1, y1) with exclusive ends
+type Rect = (u32, u32, u32, u32);
+
+/// Size of the LL band after `level` decompositions
+fn level_size(width: u32, height: u32, level: u8) -> (u32, u32) {
+    let shrink = |n: u32| (0..level).fold(n, |n, _| n.div_ceil(2));
+    (shrink(width), shrink(height))
+}
+
+/// Areas of the coefficient layout sent by `stage`
+fn stage_rects(width: u32, height: u32, levels: u8, stage: u8) -> Vec<Rect> {
+    if stage == 0 {
+        let (w, h) = level_size(width, height, levels);
+        return vec![(0, 0, w, h)]
+    }
+
+    let level = levels - stage + 1;
+    let (outer_w, outer_h) = level_size(width, height, level - 1);
+    let (w, h) = level_size(width, height, level);
+    vec![
+        // HL
+        (w, 0, outer_w, h),
+        // LH
+        (0, h, w, outer_h),
+        // HH
+        (w, h, outer_w, outer_h),
+    ]
+}
+
+/// Positions of the coefficient layout sent by `stage`, in order
+pub fn stage_positions(width: u32, height: u32, levels: u8, stage: u8) -> impl Iterator<Item = (u32, u32)> {
+    stage_rects(width, height, levels, stage).into_iter().flat_map(|(x0, y0, x1, y1)| {
+        (y0..y1).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+    })
+}
+
+/// Bytes of every position in a payload
+pub fn unit_bytes(layout: ChannelLayout) -> usize {
+    layout.channels() * coefficient_bytes(layout)
+}
+
+fn coefficient_bytes(layout: ChannelLayout) -> usize {
+    layout.bytes_per_sample() * 2
+}
+
+/// Amount of positions sent by `stage`
+pub fn stage_len(width: u32, height: u32, levels: u8, stage: u8) -> usize {
+    stage_rects(width, height, levels, stage).iter()
+        .map(|(x0, y0, x1, y1)| (x1 - x0) as usize * (y1 - y0) as usize)
+        .sum()
+}
+
+/// Coefficients of every channel, in the in-place layout
+pub struct WaveletCoefficients {
+    pub levels: u8,
+    width: u32,
+    height: u32,
+    layout: ChannelLayout,
+    channels: Vec<Vec<i64>>,
+}
+
+impl WaveletCoefficients {
+    pub fn new(image: &Raster, levels: u8) -> WaveletCoefficients {
+        let (width, height) = image.dimensions();
+        let channels = (0..image.channels()).map(|c| {
+            let mut plane: Vec<i64> = image.pixels().map(|pixel| pixel[c] as i64).collect();
+            for level in 0..levels {
+                let (w, h) = level_size(width, height, level);
+                transform_2d(&mut plane, width as usize, w as usize, h as usize, true);
+            }
+            plane
+        }).collect();
+
+        WaveletCoefficients {
+            levels,
+            width,
+            height,
+            layout: image.layout(),
+            channels,
+        }
+    }
+
+    pub fn encode_stage(&self, stage: u8) -> Vec<u8> {
+        let mut v = Vec::with_capacity(stage_len(self.width, self.height, self.levels, stage) * unit_bytes(self.layout));
+        for (x, y) in stage_positions(self.width, self.height, self.levels, stage) {
+            let i = (y * self.width + x) as usize;
+            for channel in self.channels.iter() {
+                v.extend_from_slice(&channel[i].to_le_bytes()[..coefficient_bytes(self.layout)]);
+            }
+        }
+
+        v
+    }
+}
+
+/// Keeps the coefficients received so far. Missing ones are 0, which
+/// interpolates smoothly between the ones that are known.
+pub struct WaveletDecoder {
+    levels: u8,
+    width: u32,
+    height: u32,
+    layout: ChannelLayout,
+    channels: Vec<Vec<i64>>,
+}
+
+impl WaveletDecoder {
+    pub fn new(width: u32, height: u32, layout: ChannelLayout, levels: u8) -> WaveletDecoder {
+        let len = width as usize * height as usize;
+        WaveletDecoder {
+            levels,
+            width,
+            height,
+            layout,
+            channels: vec![vec![0; len]; layout.channels()],
+        }
+    }
+
+    /// Stores the part of `stage` that starts at position `first`.
+    /// `data` must hold whole positions.
+    pub fn decode(&mut self, stage: u8, first: usize, data: &[u8]) {
+        let positions = stage_positions(self.width, self.height, self.levels, stage).skip(first);
+        let coefficient_bytes = coefficient_bytes(self.layout);
+        for ((x, y), unit) in positions.zip(data.chunks_exact(unit_bytes(self.layout))) {
+            let i = (y * self.width + x) as usize;
+            for (channel, value) in self.channels.iter_mut().zip(unit.chunks_exact(coefficient_bytes)) {
+                // Sign extended from the width on the wire
+                let fill = if value[value.len() - 1] & 0x80 != 0 { 0xFF } else { 0 };
+                let mut le_bytes = [fill; 8];
+                le_bytes[..value.len()].copy_from_slice(value);
+                channel[i] = i64::from_le_bytes(le_bytes);
+            }
+        }
+    }
+
+    /// Inverse transforms everything known into `image`
+    pub fn draw(&self, image: &mut Raster) {
+        let width = self.width as usize;
+        let max = self.layout.max_sample() as i64;
+        for (c, channel) in self.channels.iter().enumerate() {
+            let mut plane = channel.clone();
+            for level in (0..self.levels).rev() {
+                let (w, h) = level_size(self.width, self.height, level);
+                transform_2d(&mut plane, width, w as usize, h as usize, false);
+            }
+            for (pixel, value) in image.pixels_mut().zip(plane) {
+                pixel[c] = value.clamp(0, max) as u32;
+            }
+        }
+    }
+}
+
+/// Transforms the rows and then the columns of the top left `w`x`h` area of
+/// `plane`, or undoes it in the opposite order when `is_forward` is false.
+fn transform_2d(plane: &mut [i64], stride: usize, w: usize, h: usize, is_forward: bool) {
+    let transform = if is_forward { forward } else { inverse };
+    let rows = |plane: &mut [i64]| {
+        for y in 0..h {
+            transform(&mut plane[y * stride..y * stride + w]);
+        }
+    };
+    let columns = |plane: &mut [i64]| {
+        let mut column = vec![0; h];
+        for x in 0..w {
+            for y in 0..h {
+                column[y] = plane[y * stride + x];
+            }
+            transform(&mut column);
+            for y in 0..h {
+                plane[y * stride + x] = column[y];
+            }
+        }
+    };
+
+    if is_forward {
+        rows(plane);
+        columns(plane);
+    } else {
+        columns(plane);
+        rows(plane);
+    }
+}
+
+/// Splits `signal` into its low-pass half followed by its high-pass half
+fn forward(signal: &mut [i64]) {
+    let n = signal.len();
+    if n < 2 {
+        return
+    }
+    let even = |i: usize| signal[mirror(2 * i, n)];
+    let high: Vec<i64> = (0..n / 2)
+        .map(|i| signal[2 * i + 1] - (even(i) + even(i + 1)).div_euclid(2))
+        .collect();
+    let d = |i: isize| high[mirror_index(i, high.len())];
+    let low: Vec<i64> = (0..n.div_ceil(2))
+        .map(|i| signal[2 * i] + (d(i as isize - 1) + d(i as isize) + 2).div_euclid(4))
+        .collect();
+
+    signal[..low.len()].copy_from_slice(&low);
+    signal[low.len()..].copy_from_slice(&high);
+}
+
+/// Undoes [forward]
+fn inverse(signal: &mut [i64]) {
+    let n = signal.len();
+    if n < 2 {
+        return
+    }
+    let (low, high) = signal.split_at(n.div_ceil(2));
+    let d = |i: isize| high[mirror_index(i, high.len())];
+    let even: Vec<i64> = (0..low.len())
+        .map(|i| low[i] - (d(i as isize - 1) + d(i as isize) + 2).div_euclid(4))
+        .collect();
+    let even_at = |i: usize| even[mirror(2 * i, n) / 2];
+    let odd: Vec<i64> = (0..high.len())
+        .map(|i| high[i] + (even_at(i) + even_at(i + 1)).div_euclid(2))
+        .collect();
+
+    for (i, value) in even.into_iter().enumerate() {
+        signal[2 * i] = value;
+    }
+    for (i, value) in odd.into_iter().enumerate() {
+        signal[2 * i + 1] = value;
+    }
+}
+
+/// Symmetric extension of a signal of length `n`: index `n` maps to `n - 2`
+fn mirror(i: usize, n: usize) -> usize {
+    if i >= n {
+        2 * (n - 1) - i
+    } else {
+        i
+    }
+}
+
+/// Symmetric extension of the high-pass band, where index -1 maps to 0 and
+/// `len` to `len - 1`
+fn mirror_index(i: isize, len: usize) -> usize {
+    i.clamp(0, len as isize - 1) as usize
+}
@@ -0,0 +1,429 @@
+//! Wire format of the stage payloads sent from the server to the client.
+//!
+//! Every stage travels as a single packet: a fixed size header followed by
+//! the payload, either raw or coded (see [super::entropy]). Packets are sent
+//! back to back, so the receiver can decode them from a plain byte stream.
+//! All multi-byte fields are little endian.
+//!
+//! | offset | size | field                           |
+//! |--------|------|---------------------------------|
+//! | 0      | 4    | magic (`PRGL`)                  |
+//! | 4      | 1    | protocol version                |
+//! | 5      | 1    | channel layout                  |
+//! | 6      | 4    | image width                     |
+//! | 10     | 4    | image height                    |
+//! | 14     | 1    | scheme                          |
+//! | 15     | 1    | scheme parameter                |
+//! | 16     | 1    | amount of stages                |
+//! | 17     | 1    | stage                           |
+//! | 18     | 1    | payload coding                  |
+//! | 19     | 1    | predictor                       |
+//! | 20     | 1    | color transform                 |
+//! | 21     | 1    | color transform parameter       |
+//! | 22     | 4    | transfer curve                  |
+//! | 26     | 4    | payload length                  |
+//! | 30     | 4    | payload length before coding    |
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"PRGL";
+pub const PROTOCOL_VERSION: u8 = 8;
+pub const HEADER_LEN: usize = 34;
+
+/// Channels of every pixel, in order, and bits of every sample. Color is
+/// always sent as red, green and blue. Samples of more than 8 bits are sent
+/// little endian. Float samples hold linear values
+/// and are sent as the bits of an `f32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Rgba8,
+    Luma8,
+    LumaA8,
+    Rgb8,
+    Luma16,
+    LumaA16,
+    Rgb16,
+    Rgba16,
+    RgbF32,
+    RgbaF32,
+}
+
+impl ChannelLayout {
+    pub fn channels(&self) -> usize {
+        match self {
+            ChannelLayout::Luma8 | ChannelLayout::Luma16 => 1,
+            ChannelLayout::LumaA8 | ChannelLayout::LumaA16 => 2,
+            ChannelLayout::Rgb8 | ChannelLayout::Rgb16 | ChannelLayout::RgbF32 => 3,
+            ChannelLayout::Rgba8 | ChannelLayout::Rgba16 | ChannelLayout::RgbaF32 => 4,
+        }
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        match self {
+            ChannelLayout::Rgba8 | ChannelLayout::Luma8 | ChannelLayout::LumaA8 | ChannelLayout::Rgb8 => 8,
+            ChannelLayout::Luma16 | ChannelLayout::LumaA16 | ChannelLayout::Rgb16 | ChannelLayout::Rgba16 => 16,
+            ChannelLayout::RgbF32 | ChannelLayout::RgbaF32 => 32,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, ChannelLayout::RgbF32 | ChannelLayout::RgbaF32)
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample() as usize / 8
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.channels() * self.bytes_per_sample()
+    }
+
+    /// Highest value of a sample, also the mask of its bits. For float
+    /// layouts, it is the mask of the bits of an `f32`
+    pub fn max_sample(&self) -> u32 {
+        ((1u64 << self.bits_per_sample()) - 1) as u32
+    }
+
+    /// Value a sample stands for: itself, or the `f32` it holds the bits of
+    pub fn sample_value(&self, sample: u32) -> f32 {
+        if self.is_float() {
+            f32::from_bits(sample)
+        } else {
+            sample as f32
+        }
+    }
+
+    /// Undoes [ChannelLayout::sample_value], rounding and clamping `value` to
+    /// what a sample can hold. Negative values are clamped to 0 for floats too
+    pub fn sample_of_value(&self, value: f32) -> u32 {
+        if self.is_float() {
+            value.max(0.0).to_bits()
+        } else {
+            value.round().clamp(0.0, self.max_sample() as f32) as u32
+        }
+    }
+
+    /// Channels holding red, green and blue. `None` for grayscale layouts
+    pub fn rgb_channels(&self) -> Option<[usize; 3]> {
+        match self {
+            ChannelLayout::Rgba8 | ChannelLayout::Rgb8 | ChannelLayout::Rgb16 | ChannelLayout::Rgba16
+                | ChannelLayout::RgbF32 | ChannelLayout::RgbaF32 => Some([0, 1, 2]),
+            ChannelLayout::Luma8 | ChannelLayout::LumaA8 | ChannelLayout::Luma16 | ChannelLayout::LumaA16 => None,
+        }
+    }
+
+    pub fn alpha_channel(&self) -> Option<usize> {
+        match self {
+            ChannelLayout::LumaA8 | ChannelLayout::LumaA16 => Some(1),
+            ChannelLayout::Rgba8 | ChannelLayout::Rgba16 | ChannelLayout::RgbaF32 => Some(3),
+            ChannelLayout::Luma8 | ChannelLayout::Luma16 | ChannelLayout::Rgb8 | ChannelLayout::Rgb16 | ChannelLayout::RgbF32 => None,
+        }
+    }
+
+    pub fn push_sample(&self, out: &mut Vec<u8>, sample: u32) {
+        out.extend_from_slice(&sample.to_le_bytes()[..self.bytes_per_sample()]);
+    }
+
+    /// Samples of `bytes`, which must hold whole samples
+    pub fn read_samples<'a>(&self, bytes: &'a [u8]) -> impl Iterator<Item = u32> + 'a {
+        bytes.chunks_exact(self.bytes_per_sample()).map(|sample| {
+            let mut le_bytes = [0; 4];
+            le_bytes[..sample.len()].copy_from_slice(sample);
+            u32::from_le_bytes(le_bytes)
+        })
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ChannelLayout::Rgba8 => 0,
+            ChannelLayout::Luma8 => 2,
+            ChannelLayout::LumaA8 => 3,
+            ChannelLayout::Rgb8 => 4,
+            ChannelLayout::Luma16 => 5,
+            ChannelLayout::LumaA16 => 6,
+            ChannelLayout::Rgb16 => 7,
+            ChannelLayout::Rgba16 => 8,
+            ChannelLayout::RgbF32 => 9,
+            ChannelLayout::RgbaF32 => 10,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<ChannelLayout, WireError> {
+        match byte {
+            0 => Ok(ChannelLayout::Rgba8),
+            2 => Ok(ChannelLayout::Luma8),
+            3 => Ok(ChannelLayout::LumaA8),
+            4 => Ok(ChannelLayout::Rgb8),
+            5 => Ok(ChannelLayout::Luma16),
+            6 => Ok(ChannelLayout::LumaA16),
+            7 => Ok(ChannelLayout::Rgb16),
+            8 => Ok(ChannelLayout::Rgba16),
+            9 => Ok(ChannelLayout::RgbF32),
+            10 => Ok(ChannelLayout::RgbaF32),
+            other => Err(WireError::UnknownLayout(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Raw,
+    /// Adaptive arithmetic coding, see [super::entropy]
+    Arithmetic,
+}
+
+impl Coding {
+    fn to_byte(self) -> u8 {
+        match self {
+            Coding::Raw => 0,
+            Coding::Arithmetic => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Coding, WireError> {
+        match byte {
+            0 => Ok(Coding::Raw),
+            1 => Ok(Coding::Arithmetic),
+            other => Err(WireError::UnknownCoding(other)),
+        }
+    }
+}
+
+/// What the values of a payload are relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predictor {
+    /// Absolute values
+    None,
+    /// Residuals from the pixels of the previous stages, see [super::predict]
+    Hierarchical,
+}
+
+impl Predictor {
+    fn to_byte(self) -> u8 {
+        match self {
+            Predictor::None => 0,
+            Predictor::Hierarchical => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Predictor, WireError> {
+        match byte {
+            0 => Ok(Predictor::None),
+            1 => Ok(Predictor::Hierarchical),
+            other => Err(WireError::UnknownPredictor(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub width: u32,
+    pub height: u32,
+    pub layout: ChannelLayout,
+    pub scheme: u8,
+    /// Meaning depends on the scheme
+    pub scheme_param: u8,
+    pub stage_count: u8,
+    pub stage: u8,
+    pub coding: Coding,
+    pub predictor: Predictor,
+    pub color: u8,
+    /// Meaning depends on the color transform
+    pub color_param: u8,
+    /// See [super::transfer::Transfer::to_wire]
+    pub transfer: u32,
+    pub payload_len: u32,
+    /// Equal to `payload_len` for raw payloads
+    pub raw_len: u32,
+}
+
+impl PacketHeader {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&MAGIC);
+        out.push(PROTOCOL_VERSION);
+        out.push(self.layout.to_byte());
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.push(self.scheme);
+        out.push(self.scheme_param);
+        out.push(self.stage_count);
+        out.push(self.stage);
+        out.push(self.coding.to_byte());
+        out.push(self.predictor.to_byte());
+        out.push(self.color);
+        out.push(self.color_param);
+        out.extend_from_slice(&self.transfer.to_le_bytes());
+        out.extend_from_slice(&self.payload_len.to_le_bytes());
+        out.extend_from_slice(&self.raw_len.to_le_bytes());
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<PacketHeader, WireError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(WireError::Truncated { expected: HEADER_LEN, got: bytes.len() })
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(WireError::BadMagic)
+        }
+        if bytes[4] != PROTOCOL_VERSION {
+            return Err(WireError::UnsupportedVersion(bytes[4]))
+        }
+
+        Ok(PacketHeader {
+            layout: ChannelLayout::from_byte(bytes[5])?,
+            width: read_u32(&bytes[6..10]),
+            height: read_u32(&bytes[10..14]),
+            scheme: bytes[14],
+            scheme_param: bytes[15],
+            stage_count: bytes[16],
+            stage: bytes[17],
+            coding: Coding::from_byte(bytes[18])?,
+            predictor: Predictor::from_byte(bytes[19])?,
+            color: bytes[20],
+            color_param: bytes[21],
+            transfer: read_u32(&bytes[22..26]),
+            payload_len: read_u32(&bytes[26..30]),
+            raw_len: read_u32(&bytes[30..34]),
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Frames `payload` behind `header`. The `payload_len` of the header is
+/// overwritten with the actual length of `payload`.
+pub fn encode(header: PacketHeader, payload: &[u8]) -> Vec<u8> {
+    let header = PacketHeader {
+        payload_len: payload.len() as u32,
+        ..header
+    };
+    let mut v = Vec::with_capacity(HEADER_LEN + payload.len());
+    header.write(&mut v);
+    v.extend_from_slice(payload);
+
+    v
+}
+
+/// Decodes a stream of packets that arrives in chunks of any size.
+///
+/// The payload of a packet can be consumed before all of it has arrived.
+#[derive(Debug, Default)]
+pub struct PacketStream {
+    /// Bytes received but not consumed yet
+    buffer: Vec<u8>,
+    /// Packet being received
+    header: Option<PacketHeader>,
+    /// Bytes of the current payload not consumed yet
+    payload_left: usize,
+    /// Whether the rest of the current payload must be discarded
+    skipping: bool,
+}
+
+impl PacketStream {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes)
+    }
+
+    /// Header of the packet being received, or `None` if it hasn't arrived yet.
+    ///
+    /// A malformed header makes the stream unrecoverable, so everything
+    /// buffered is dropped.
+    pub fn header(&mut self) -> Result<Option<PacketHeader>, WireError> {
+        if self.skipping {
+            let n = self.buffer.len().min(self.payload_left);
+            self.buffer.drain(..n);
+            self.payload_left -= n;
+            if self.payload_left > 0 {
+                return Ok(None)
+            }
+            self.skipping = false;
+            self.header = None;
+        }
+
+        if self.header.is_none() && self.buffer.len() >= HEADER_LEN {
+            match PacketHeader::read(&self.buffer) {
+                Ok(header) => {
+                    self.buffer.drain(..HEADER_LEN);
+                    self.payload_left = header.payload_len as usize;
+                    self.header = Some(header);
+                },
+                Err(e) => {
+                    self.buffer.clear();
+                    return Err(e)
+                },
+            }
+        }
+
+        Ok(self.header)
+    }
+
+    /// Takes the part of the current payload that has arrived, rounded down
+    /// to a multiple of `unit` bytes.
+    pub fn take_payload(&mut self, unit: usize) -> Vec<u8> {
+        if self.header.is_none() || self.skipping {
+            return Vec::new()
+        }
+        let available = self.buffer.len().min(self.payload_left);
+        let n = available - available % unit;
+        self.payload_left -= n;
+
+        self.buffer.drain(..n).collect()
+    }
+
+    /// Bytes of the current payload that haven't been taken yet
+    pub fn payload_left(&self) -> usize {
+        self.payload_left
+    }
+
+    /// Bytes received that haven't been consumed yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Closes the current packet if all of its payload has been taken
+    pub fn finish_packet(&mut self) -> bool {
+        if self.header.is_some() && !self.skipping && self.payload_left == 0 {
+            self.header = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Discards the rest of the current packet, including the parts that
+    /// haven't arrived yet
+    pub fn skip_packet(&mut self) {
+        if self.header.is_some() {
+            self.skipping = true;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = PacketStream::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownLayout(u8),
+    UnknownCoding(u8),
+    UnknownPredictor(u8),
+    Truncated { expected: usize, got: usize },
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::BadMagic => write!(f, "not a stage packet (bad magic)"),
+            WireError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v} (expected {PROTOCOL_VERSION})"),
+            WireError::UnknownLayout(l) => write!(f, "unknown channel layout {l}"),
+            WireError::UnknownCoding(c) => write!(f, "unknown payload coding {c}"),
+            WireError::UnknownPredictor(p) => write!(f, "unknown predictor {p}"),
+            WireError::Truncated { expected, got } => write!(f, "truncated packet: expected {expected} bytes, got {got}"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
@@ -0,0 +1,556 @@
+//! Round trips of generated images through an [Encoder] and a [Decoder].
+//!
+//! Lossless schemes must rebuild the image bit for bit, at any size and in
+//! any layout, however the packet stream is split. Every intermediate stage
+//! is checked against `golden/stages.txt`, which holds a digest of the
+//! samples the decoder has after each stage. Run with `UPDATE_GOLDEN=1` to
+//! write it again after an intended change of the output. The golden digests
+//! come from the decoder itself, so a few stages of every scheme are also
+//! checked against values worked out by hand.
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use progressive_codec::{BlurKernel, ChannelLayout, ColorTransform, Decoder, Encoder, Ladder, Raster, ReceiveError, Reconstruction, Rect, Scheme};
+
+const GOLDEN: &str = include_str!("golden/stages.txt");
+
+/// Includes odd, prime and power of two dimensions, and one past them
+const SIZES: [u32; 12] = [1, 2, 3, 5, 7, 8, 13, 16, 17, 31, 64, 67];
+
+const LAYOUTS: [ChannelLayout; 10] = [
+    ChannelLayout::Rgba8,
+    ChannelLayout::Luma8,
+    ChannelLayout::LumaA8,
+    ChannelLayout::Rgb8,
+    ChannelLayout::Luma16,
+    ChannelLayout::LumaA16,
+    ChannelLayout::Rgb16,
+    ChannelLayout::Rgba16,
+    ChannelLayout::RgbF32,
+    ChannelLayout::RgbaF32,
+];
+
+struct Config {
+    name: &'static str,
+    scheme: Scheme,
+    color: ColorTransform,
+    entropy_coding: bool,
+    residual_prediction: bool,
+    reconstruction: Reconstruction,
+    blur_kernel: BlurKernel,
+}
+
+impl Config {
+    const fn new(name: &'static str, scheme: Scheme) -> Config {
+        Config {
+            name,
+            scheme,
+            color: ColorTransform::None,
+            entropy_coding: true,
+            residual_prediction: true,
+            reconstruction: Reconstruction::Nearest,
+            blur_kernel: BlurKernel { auto: true, radius: 2, sigma: 1.0 },
+        }
+    }
+
+    const fn color(self, color: ColorTransform) -> Config {
+        Config { color, ..self }
+    }
+
+    const fn raw(self) -> Config {
+        Config { entropy_coding: false, residual_prediction: false, ..self }
+    }
+
+    const fn reconstruction(self, reconstruction: Reconstruction) -> Config {
+        Config { reconstruction, ..self }
+    }
+
+    const fn blur_kernel(self, blur_kernel: BlurKernel) -> Config {
+        Config { blur_kernel, ..self }
+    }
+
+    fn is_lossless(&self) -> bool {
+        !matches!(self.scheme, Scheme::Dct { .. })
+    }
+}
+
+fn configs() -> Vec<Config> {
+    vec![
+        Config::new("blocks", Scheme::Blocks(Ladder::default())),
+        Config::new("blocks-raw", Scheme::Blocks(Ladder::new(3, 4))).raw(),
+        Config::new("blocks-sparse", Scheme::Blocks(Ladder::new(5, 3))),
+        Config::new("blocks-ycocg", Scheme::Blocks(Ladder::new(4, 5))).color(ColorTransform::YCoCgR { chroma_lag: 1 }),
+        Config::new("blocks-ycocg-raw", Scheme::Blocks(Ladder::new(2, 3))).color(ColorTransform::YCoCgR { chroma_lag: 2 }).raw(),
+        Config::new("adam7", Scheme::Adam7),
+        Config::new("adam7-ycocg-raw", Scheme::Adam7).color(ColorTransform::YCoCgR { chroma_lag: 1 }).raw(),
+        Config::new("dct", Scheme::Dct { quality: 75 }),
+        Config::new("dct-raw", Scheme::Dct { quality: 20 }).raw(),
+        Config::new("wavelet-1", Scheme::Wavelet { levels: 1 }),
+        Config::new("wavelet-5-raw", Scheme::Wavelet { levels: 5 }).raw(),
+        Config::new("bitplanes-1", Scheme::BitPlanes { bits_per_stage: 1 }),
+        Config::new("bitplanes-3-raw", Scheme::BitPlanes { bits_per_stage: 3 }).raw(),
+    ]
+}
+
+/// Smooth gradients with some noise on top, so predictions are neither
+/// always right nor always wrong
+fn generate(width: u32, height: u32, layout: ChannelLayout) -> Raster {
+    let mut image = Raster::new(width, height, layout);
+    let max = layout.max_sample() as u64;
+    for y in 0..height {
+        for x in 0..width {
+            let noise = (x as u64 * 7919 + y as u64 * 104_729) % 13;
+            for (c, sample) in image.pixel_mut(x, y).iter_mut().enumerate() {
+                let gradient = x as u64 * (c as u64 + 3) + y as u64 * (5 - c as u64);
+                *sample = if layout.is_float() {
+                    ((gradient + noise) as f32 / 37.0).to_bits()
+                } else {
+                    ((gradient * 5 + noise * 3) * (max / 255).max(1) % (max + 1)) as u32
+                };
+            }
+        }
+    }
+
+    image
+}
+
+/// Sends `image` whole, handing the packets to the decoder in pieces of
+/// `chunk` bytes, and calls `stage_done` after every stage
+fn transmit(image: &Raster, config: &Config, chunk: usize, mut stage_done: impl FnMut(u8, &Decoder)) -> Decoder {
+    let mut encoder = Encoder::new(image.clone());
+    encoder.set_scheme(config.scheme);
+    encoder.set_color(config.color);
+    encoder.entropy_coding = config.entropy_coding;
+    encoder.residual_prediction = config.residual_prediction;
+    let (width, height) = image.dimensions();
+    let mut decoder = Decoder::new(width, height);
+    decoder.reconstruction = config.reconstruction;
+    decoder.blur_kernel = config.blur_kernel;
+
+    loop {
+        let stage = encoder.stage();
+        let Some(packet) = encoder.send() else { break };
+        for piece in packet.chunks(chunk) {
+            if let Err(e) = decoder.receive(piece) {
+                panic!("{} {width}x{height} {:?}: stage {stage} rejected: {e}", config.name, image.layout())
+            }
+        }
+        stage_done(stage, &decoder);
+    }
+    assert!(decoder.is_complete(), "{} {width}x{height}: the decoder expects more stages", config.name);
+
+    decoder
+}
+
+fn assert_same(image: &Raster, decoder: &Decoder, what: &str) {
+    assert_eq!(decoder.image().layout(), image.layout(), "{what}: layout");
+    assert_eq!(decoder.image().transfer(), image.transfer(), "{what}: transfer curve");
+    let mismatch = image.samples().iter().zip(decoder.image().samples()).position(|(a, b)| a != b);
+    if let Some(i) = mismatch {
+        let channels = image.channels();
+        let (x, y) = ((i / channels) as u32 % image.width(), (i / channels) as u32 / image.width());
+        panic!("{what}: pixel ({x}, {y}) is {:?} instead of {:?}", decoder.image().pixel(x, y), image.pixel(x, y))
+    }
+}
+
+/// FNV-1a of every sample
+fn digest(image: &Raster) -> u64 {
+    image.samples().iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[test]
+fn every_size_round_trips() {
+    for &width in SIZES.iter() {
+        for &height in SIZES.iter() {
+            let image = generate(width, height, ChannelLayout::Rgb8);
+            for config in configs() {
+                let decoder = transmit(&image, &config, usize::MAX, |_, _| ());
+                if config.is_lossless() {
+                    assert_same(&image, &decoder, &format!("{} {width}x{height}", config.name));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn every_layout_round_trips() {
+    for layout in LAYOUTS {
+        for (width, height) in [(1, 1), (2, 3), (23, 17), (67, 5)] {
+            let image = generate(width, height, layout);
+            for config in configs().iter().filter(|config| config.is_lossless()) {
+                let decoder = transmit(&image, config, usize::MAX, |_, _| ());
+                assert_same(&image, &decoder, &format!("{} {width}x{height} {layout:?}", config.name));
+            }
+        }
+    }
+}
+
+#[test]
+fn split_stream_gives_the_same_stages() {
+    let image = generate(19, 13, ChannelLayout::Rgba8);
+    for config in configs() {
+        let mut whole = Vec::new();
+        transmit(&image, &config, usize::MAX, |_, decoder| whole.push(digest(decoder.image())));
+        for chunk in [1, 3, 64] {
+            let mut split = Vec::new();
+            transmit(&image, &config, chunk, |_, decoder| split.push(digest(decoder.image())));
+            assert_eq!(split, whole, "{} in pieces of {chunk} bytes", config.name);
+        }
+    }
+}
+
+/// Without a color transform, every pixel of a block ladder shows the sample
+/// at the corner of its block until a finer stage arrives
+#[test]
+fn block_stages_show_their_corners() {
+    let ladders = [Ladder::default(), Ladder::new(3, 4), Ladder::new(5, 3), Ladder::new(0, 1)];
+    for (width, height) in [(1, 1), (3, 2), (17, 31), (67, 64)] {
+        let image = generate(width, height, ChannelLayout::Rgb8);
+        for ladder in ladders {
+            let block_sizes: Vec<u32> = ladder.block_sizes().collect();
+            for config in [Config::new("blocks", Scheme::Blocks(ladder)), Config::new("blocks-raw", Scheme::Blocks(ladder)).raw()] {
+                transmit(&image, &config, usize::MAX, |stage, decoder| {
+                    let block = block_sizes[stage as usize];
+                    for y in 0..height {
+                        for x in 0..width {
+                            assert_eq!(
+                                decoder.image().pixel(x, y), image.pixel(x - x % block, y - y % block),
+                                "{} {ladder:?} {width}x{height}, stage {stage}: pixel ({x}, {y})", config.name,
+                            );
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// After every Adam7 stage, the pixels of the passes sent so far are known
+/// with their true value and no other pixel is
+#[test]
+fn adam7_stages_know_their_passes() {
+    let image = generate(21, 13, ChannelLayout::Rgb8);
+    for config in [Config::new("adam7", Scheme::Adam7), Config::new("adam7-raw", Scheme::Adam7).raw()] {
+        transmit(&image, &config, usize::MAX, |stage, decoder| {
+            for y in 0..image.height() {
+                for x in 0..image.width() {
+                    let sent = ADAM7_PASSES[..=stage as usize].iter()
+                        .any(|((origin_x, origin_y), (step_x, step_y))| {
+                            x >= *origin_x && y >= *origin_y && (x - origin_x) % step_x == 0 && (y - origin_y) % step_y == 0
+                        });
+                    assert_eq!(decoder.is_known(x, y), sent, "{}, stage {stage}: pixel ({x}, {y})", config.name);
+                    if sent {
+                        assert_eq!(decoder.image().pixel(x, y), image.pixel(x, y), "{}, stage {stage}: pixel ({x}, {y})", config.name);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// A flat block only has a DC coefficient. At quality 50 the luma step is 16,
+/// so 176 quantizes to (176 - 128) * 8 / 16 = 24. The first scan leaves out
+/// its lowest bit and the decoder guesses 24.5, shown as 128 + 24.5 * 16 / 8
+/// = 177, until the DC refinement of stage 4 brings it back to 176
+#[test]
+fn dct_dc_of_a_flat_image() {
+    let (width, height) = (19, 10);
+    let mut image = Raster::new(width, height, ChannelLayout::Luma8);
+    for y in 0..height {
+        for x in 0..width {
+            image.pixel_mut(x, y)[0] = 176;
+        }
+    }
+    for config in [Config::new("dct", Scheme::Dct { quality: 50 }), Config::new("dct-raw", Scheme::Dct { quality: 50 }).raw()] {
+        transmit(&image, &config, usize::MAX, |stage, decoder| {
+            let expected = if stage < 4 { 177 } else { 176 };
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(decoder.image().pixel(x, y), [expected], "{}, stage {stage}: pixel ({x}, {y})", config.name);
+                }
+            }
+        });
+    }
+}
+
+/// Rows of 10, 20, 40, 30 give the 5/3 high-pass coefficients
+/// 20 - (10 + 40) / 2 = -5 and 30 - (40 + 40) / 2 = -10, and the low-pass
+/// (LL) ones 10 + (-5 - 5 + 2) / 4 = 8 and 40 + (-5 - 10 + 2) / 4 = 36,
+/// rounding down. Stage 0 only sends the LL band, so the even columns show it
+/// and the odd ones the mean of their neighbors, 22 and 36
+#[test]
+fn wavelet_stage_0_shows_the_ll_band() {
+    let row = [10, 20, 40, 30];
+    let mut image = Raster::new(4, 4, ChannelLayout::Luma8);
+    for y in 0..4 {
+        for (x, value) in row.into_iter().enumerate() {
+            image.pixel_mut(x as u32, y)[0] = value;
+        }
+    }
+    for config in [Config::new("wavelet-1", Scheme::Wavelet { levels: 1 }), Config::new("wavelet-1-raw", Scheme::Wavelet { levels: 1 }).raw()] {
+        transmit(&image, &config, usize::MAX, |stage, decoder| {
+            let expected = if stage == 0 { [8, 22, 36, 36] } else { row };
+            for y in 0..4 {
+                let got: Vec<u32> = (0..4).map(|x| decoder.image().pixel(x, y)[0]).collect();
+                assert_eq!(got, expected, "{}, stage {stage}: row {y}", config.name);
+            }
+        });
+    }
+}
+
+/// Every mode interpolates between the samples received, never over them
+#[test]
+fn reconstruction_keeps_received_pixels() {
+    let ladder = Ladder::new(4, 5);
+    let block_sizes: Vec<u32> = ladder.block_sizes().collect();
+    let image = generate(37, 29, ChannelLayout::Rgba8);
+    for mode in Reconstruction::ALL {
+        let config = Config::new("blocks", Scheme::Blocks(ladder)).reconstruction(mode);
+        let decoder = transmit(&image, &config, usize::MAX, |stage, decoder| {
+            let display = decoder.display(false);
+            let block = block_sizes[stage as usize];
+            for y in (0..image.height()).step_by(block as usize) {
+                for x in (0..image.width()).step_by(block as usize) {
+                    let expected = image.pixel(x, y).iter().map(|&sample| sample as u8).collect::<Vec<_>>();
+                    assert_eq!(display.get_pixel(x, y).0[..], expected[..], "{mode:?}, stage {stage}: pixel ({x}, {y})");
+                }
+            }
+        });
+        assert_eq!(decoder.display(false).as_raw(), &image.samples().iter().map(|&sample| sample as u8).collect::<Vec<_>>(), "{mode:?}");
+    }
+}
+
+/// Kernels are normalized, so blurring a flat picture leaves it as it is
+#[test]
+fn blur_keeps_flat_pictures() {
+    let mut image = Raster::new(23, 19, ChannelLayout::Rgb8);
+    image.samples_mut().iter_mut().enumerate().for_each(|(i, sample)| *sample = [40, 200, 90][i % 3]);
+    let kernels = [
+        BlurKernel::default(),
+        BlurKernel { auto: false, radius: 5, sigma: 2.5 },
+        BlurKernel { auto: false, radius: 2, sigma: 15.0 },
+        BlurKernel { auto: false, radius: 0, sigma: 1.0 },
+    ];
+    for kernel in kernels {
+        let weights = kernel.weights(8);
+        assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-5, "{kernel:?}");
+        let config = Config::new("blocks", Scheme::Blocks(Ladder::new(4, 5))).blur_kernel(kernel);
+        transmit(&image, &config, usize::MAX, |stage, decoder| {
+            assert_eq!(decoder.display(true), decoder.display(false), "{kernel:?}, stage {stage}");
+        });
+    }
+}
+
+/// Pixels received exactly show their true value, blurred or not
+#[test]
+fn blur_keeps_known_pixels() {
+    let image = generate(29, 21, ChannelLayout::Rgba8);
+    let kernel = BlurKernel { auto: false, radius: 3, sigma: 2.0 };
+    for config in configs().into_iter().filter(|config| matches!(config.scheme, Scheme::Blocks(_) | Scheme::Adam7)) {
+        let config = config.blur_kernel(kernel);
+        let mut known = 0;
+        transmit(&image, &config, usize::MAX, |stage, decoder| {
+            let blurred = decoder.display(true);
+            for (x, y, pixel) in blurred.enumerate_pixels().filter(|(x, y, _)| decoder.is_known(*x, *y)) {
+                let expected = image.pixel(x, y).iter().map(|&sample| sample as u8).collect::<Vec<_>>();
+                assert_eq!(pixel.0[..], expected[..], "{}, stage {stage}: pixel ({x}, {y})", config.name);
+            }
+            let now = blurred.enumerate_pixels().filter(|(x, y, _)| decoder.is_known(*x, *y)).count();
+            assert!(now >= known, "{}, stage {stage}: pixels were forgotten", config.name);
+            known = now;
+        });
+    }
+}
+
+/// Every pixel shown differently after a piece of the stream lies in the
+/// rectangle the decoder reports, and the picture it keeps drawn again inside
+/// that rectangle matches the whole picture drawn from scratch
+#[test]
+fn dirty_rect_holds_every_change() {
+    let image = generate(41, 27, ChannelLayout::Rgb8);
+    for config in configs() {
+        for (reconstruction, blur) in [(Reconstruction::Nearest, false), (Reconstruction::Bicubic, true), (Reconstruction::EdgeDirected, false)] {
+            let mut encoder = Encoder::new(image.clone());
+            encoder.set_scheme(config.scheme);
+            encoder.set_color(config.color);
+            encoder.entropy_coding = config.entropy_coding;
+            encoder.residual_prediction = config.residual_prediction;
+            let mut decoder = Decoder::new(image.width(), image.height());
+            decoder.reconstruction = reconstruction;
+            decoder.blur = blur;
+            decoder.blur_kernel = BlurKernel { auto: false, radius: 2, sigma: 1.5 };
+            assert_eq!(decoder.take_dirty(), Some(Rect::whole(image.width(), image.height())));
+
+            let mut shown = decoder.display(blur);
+            assert!(*decoder.shown() == shown, "{} {reconstruction:?}: kept picture at the start", config.name);
+            while let Some(packet) = encoder.send() {
+                for piece in packet.chunks(53) {
+                    decoder.receive(piece).unwrap();
+                    let now = decoder.display(blur);
+                    let dirty = decoder.take_dirty();
+                    for (x, y, pixel) in now.enumerate_pixels().filter(|(x, y, pixel)| *pixel != shown.get_pixel(*x, *y)) {
+                        let inside = dirty.is_some_and(|dirty| {
+                            (dirty.x..dirty.x + dirty.width).contains(&x) && (dirty.y..dirty.y + dirty.height).contains(&y)
+                        });
+                        assert!(inside, "{} {reconstruction:?}: pixel ({x}, {y}) became {pixel:?} outside {dirty:?}", config.name);
+                    }
+                    assert!(*decoder.shown() == now, "{} {reconstruction:?}: kept picture differs after {dirty:?}", config.name);
+                    shown = now;
+                }
+            }
+        }
+    }
+}
+
+/// Stage 0 of a transmission of `layout`, whose header claims one more raw
+/// byte than the stage holds
+fn bad_length_packet(layout: ChannelLayout) -> Vec<u8> {
+    let mut encoder = Encoder::new(generate(13, 9, layout));
+    encoder.set_scheme(Scheme::Dct { quality: 50 });
+    let mut packet = encoder.send().unwrap();
+    let raw_len = u32::from_le_bytes(packet[30..34].try_into().unwrap());
+    packet[30..34].copy_from_slice(&(raw_len + 1).to_le_bytes());
+    packet
+}
+
+/// A packet that is rejected leaves the decoder as it was, whether it waits
+/// for a new transmission or is in the middle of one
+#[test]
+fn rejected_packet_changes_nothing() {
+    let image = generate(13, 9, ChannelLayout::Rgb16);
+    let config = Config::new("wavelet", Scheme::Wavelet { levels: 2 });
+
+    // Waits for stage 0, at the layout of the transmission before
+    let mut decoder = transmit(&image, &config, usize::MAX, |_, _| ());
+    decoder.clear();
+    decoder.take_dirty();
+    let result = decoder.receive(&bad_length_packet(ChannelLayout::Rgb8));
+    assert!(matches!(result, Err(ReceiveError::PayloadLength { .. })), "{result:?}");
+    assert_eq!(decoder.layout(), ChannelLayout::Rgb16);
+    assert_eq!(decoder.take_dirty(), None);
+
+    let mut encoder = Encoder::new(image.clone());
+    encoder.set_scheme(config.scheme);
+    decoder.receive(&encoder.send().unwrap()).unwrap();
+    let placed = decoder.image().clone();
+    assert!(decoder.receive(&bad_length_packet(ChannelLayout::Rgb16)).is_err());
+    assert_eq!(decoder.image(), &placed);
+    while let Some(packet) = encoder.send() {
+        decoder.receive(&packet).unwrap();
+    }
+    assert_same(&image, &decoder, "wavelet after rejected packets");
+}
+
+/// The first packet of a transmission starts it once, the pieces of its
+/// payload that follow only change the rows they place
+#[test]
+fn first_packet_starts_once() {
+    let image = generate(64, 64, ChannelLayout::Rgb8);
+    let mut encoder = Encoder::new(image.clone());
+    encoder.set_scheme(Scheme::Adam7);
+    encoder.entropy_coding = false;
+    encoder.residual_prediction = false;
+    let mut decoder = Decoder::new(image.width(), image.height());
+    decoder.take_dirty();
+
+    // Stage 0 has a pixel every 8 in each direction, 8 of them per row
+    let packet = encoder.send().unwrap();
+    let (header, payload) = packet.split_at(34 + 1);
+    decoder.receive(header).unwrap();
+    assert_eq!(decoder.take_dirty(), Some(Rect::whole(64, 64)));
+    decoder.receive(&payload[..16 * 3 - 1]).unwrap();
+    assert_eq!(decoder.take_dirty(), Some(Rect { x: 0, y: 0, width: 64, height: 16 }));
+    decoder.receive(&payload[16 * 3 - 1..]).unwrap();
+    while let Some(packet) = encoder.send() {
+        decoder.receive(&packet).unwrap();
+    }
+    assert_same(&image, &decoder, "adam7 with a split first packet");
+}
+
+/// Origin and step of every Adam7 pass
+const ADAM7_PASSES: [((u32, u32), (u32, u32)); 7] = [
+    ((0, 0), (8, 8)),
+    ((4, 0), (8, 8)),
+    ((0, 4), (4, 8)),
+    ((2, 0), (4, 4)),
+    ((0, 2), (2, 4)),
+    ((1, 0), (2, 2)),
+    ((0, 1), (1, 2)),
+];
+
+/// An interlaced PNG streams its passes as stored, whatever the prediction
+/// and color transform settings
+#[test]
+fn png_passes_go_out_as_stored() {
+    let image = generate(21, 13, ChannelLayout::Rgb16);
+    let decoder = transmit(&image, &Config::new("adam7", Scheme::Adam7), usize::MAX, |_, _| ());
+    let path = std::env::temp_dir().join(format!("progressive-codec-passes-{}.png", std::process::id()));
+    decoder.export_png(&path).unwrap();
+    let opened = Encoder::open(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let mut encoder = opened.unwrap();
+    assert!(encoder.has_png_passes());
+    assert!(encoder.residual_prediction, "prediction is on by default");
+    encoder.set_scheme(Scheme::Adam7);
+    encoder.set_color(ColorTransform::YCoCgR { chroma_lag: 1 });
+    encoder.entropy_coding = false;
+    for (stage, ((origin_x, origin_y), (step_x, step_y))) in ADAM7_PASSES.into_iter().enumerate() {
+        let mut pass = Vec::new();
+        for y in (origin_y..image.height()).step_by(step_y as usize) {
+            for x in (origin_x..image.width()).step_by(step_x as usize) {
+                pass.extend(image.pixel(x, y).iter().flat_map(|&sample| (sample as u16).to_le_bytes()));
+            }
+        }
+        let packet = encoder.send().unwrap();
+        assert_eq!(packet[19], 0, "stage {stage}: predictor");
+        assert_eq!(packet[20], 0, "stage {stage}: color transform");
+        assert_eq!(&packet[34..], &pass[..], "stage {stage}: payload");
+    }
+    assert!(encoder.is_complete());
+    assert_eq!(encoder.send(), None);
+}
+
+#[test]
+fn stages_match_golden() {
+    let images = [
+        generate(37, 23, ChannelLayout::Rgb8),
+        generate(19, 11, ChannelLayout::Rgba16),
+        generate(13, 7, ChannelLayout::RgbF32),
+        generate(9, 14, ChannelLayout::LumaA8),
+    ];
+    let mut digests = String::new();
+    for image in images.iter() {
+        for config in configs() {
+            let (width, height) = image.dimensions();
+            transmit(image, &config, usize::MAX, |stage, decoder| {
+                let _ = writeln!(digests, "{} {:?} {width}x{height} {stage} {:016x}", config.name, image.layout(), digest(decoder.image()));
+            });
+        }
+    }
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/stages.txt");
+        std::fs::write(path, &digests).unwrap();
+        return
+    }
+
+    // Keyed by everything but the digest, so a mismatch names the stage
+    let entries = |text: &str| -> BTreeMap<String, String> {
+        text.lines().filter_map(|line| {
+            let (key, digest) = line.rsplit_once(' ')?;
+            Some((key.to_string(), digest.to_string()))
+        }).collect()
+    };
+    let expected = entries(GOLDEN);
+    let got = entries(&digests);
+    for (key, digest) in got.iter() {
+        match expected.get(key) {
+            Some(expected) => assert_eq!(digest, expected, "{key} differs from the golden digest"),
+            None => panic!("{key} has no golden digest"),
+        }
+    }
+    assert_eq!(got.len(), expected.len(), "stages were dropped");
+}
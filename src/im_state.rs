@@ -1,14 +1,22 @@
-use imgui::Context;
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use imgui::{Context, TextureId};
 use imgui_wgpu::{Renderer, RendererConfig};
 use imgui_winit_support::WinitPlatform;
 use te_renderer::{initial_config::InitialConfiguration, state::{GpuState, TeState}};
-use wgpu::{Device, Queue};
 use winit::{window::Window, event::WindowEvent};
 
 use crate::ActionTaken;
 
-use self::data::{DataState, ServerData, ClientData, SendStage};
+use progressive_codec::{BlurKernel, ColorTransform, Decoder, Encoder, Ladder, Quality, Reconstruction, Scheme, SequenceFormat, ToneOperator};
+
+use self::data::{DataState, DEFAULT_IMAGE};
+use self::netsim::{Bandwidth, LinkProfile, LinkSimulator, ThroughputTrace, PRESETS};
+use self::transport::Transport;
+pub use self::transport::TransportKind;
 mod data;
+mod netsim;
+mod transport;
 
 pub struct ImState {
     gpu: GpuState,
@@ -17,11 +25,23 @@ pub struct ImState {
     renderer: Renderer,
     pub state: TeState,
 
-    data: DataState
+    data: DataState,
+    autoplay: Autoplay,
+    /// Sits between the server and `server_link`
+    link: LinkSimulator,
+    sender_ui: SenderUi,
+    receiver_ui: ReceiverUi,
+    /// Endpoint the server writes its packets to
+    server_link: Box<dyn Transport>,
+    /// Endpoint the client reads its packets from
+    client_link: Box<dyn Transport>,
+    /// Kind of the connection between `server_link` and `client_link`
+    transport: TransportKind,
 }
 
 impl ImState {
-    pub(crate) async fn new(window: &Window, config: InitialConfiguration) -> ImState {
+    /// Opens `image`, or [DEFAULT_IMAGE] if there's none
+    pub(crate) async fn new(window: &Window, config: InitialConfiguration, transport: TransportKind, image: Option<PathBuf>) -> ImState {
         let size = window.inner_size();
         let gpu = GpuState::new(size, window).await;
         let state = TeState::new(window, &gpu, config.clone()).await;
@@ -36,15 +56,26 @@ impl ImState {
         };
 
         let mut renderer = Renderer::new(&mut context, &gpu.device, &gpu.queue, renderer_config);
+        let (server_link, client_link) = transport.connect().expect("Failed to open transport");
 
-        ImState {
-            data: DataState::new(&gpu.device, &mut renderer, &gpu.queue),
+        let mut im_state = ImState {
+            data: DataState::new(&gpu.device, &mut renderer, &gpu.queue, gpu.config.format),
+            autoplay: Autoplay::default(),
+            link: LinkSimulator::new(LinkProfile::instant()),
+            sender_ui: SenderUi::default(),
+            receiver_ui: ReceiverUi::default(),
+            server_link,
+            client_link,
+            transport,
             gpu,
             context,
             platform,
             renderer,
             state,
-        }
+        };
+        im_state.open_image(image.unwrap_or_else(|| PathBuf::from(DEFAULT_IMAGE)));
+
+        im_state
     }
 
     pub(crate) fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -60,6 +91,23 @@ impl ImState {
 
     pub fn update(&mut self, dt: std::time::Duration) {
         self.state.update(dt, &self.gpu);
+
+        let link_idle = self.link.pending() == 0;
+        for _ in 0..self.autoplay.tick(dt, link_idle) {
+            if self.data.server.is_complete() {
+                self.autoplay.playing = false;
+                break
+            }
+            self.send();
+        }
+
+        let delivered = self.link.advance(dt);
+        if !delivered.is_empty() {
+            if let Err(e) = self.server_link.send(&delivered) {
+                eprintln!("Failed to send packet: {e}")
+            }
+        }
+        self.receive_packets();
     }
 
     pub(crate) fn render(&mut self, window: &Window) -> Result<Option<ActionTaken>, wgpu::SurfaceError> {
@@ -78,19 +126,22 @@ impl ImState {
         ui.dockspace_over_main_viewport();
         let action_taken = {
             let mut action_taken = None;
+            let (server_texture, client_texture) = (self.data.server_texture(), self.data.client_texture());
 
-            if let Some(action) = sender_window(ui, &self.data.server) {
+            if let Some(action) = sender_window(ui, &mut self.data.server, server_texture, &mut self.autoplay, &mut self.link, &mut self.sender_ui) {
                 if let None = action_taken {
                     action_taken = Some(action);
                 }
             };
 
-            if let Some(action) = receiver_window(ui, &mut self.data.client, &self.gpu.device, &mut self.renderer, &self.gpu.queue) {
+            if let Some(action) = receiver_window(ui, &mut self.data.client, client_texture, &mut self.receiver_ui) {
                 if let None = action_taken {
                     action_taken = Some(action);
                 }
             };
 
+            self.data.update_client_texture(&self.gpu.device, &mut self.renderer, &self.gpu.queue);
+
             action_taken
         };
 
@@ -118,14 +169,67 @@ impl ImState {
         action_taken
     }
 
+    /// Puts the next stage on the link, it reaches the client over the
+    /// following frames
     pub(crate) fn send(&mut self) {
-        let data = self.data.server.send();
-        self.data.client.receive(&self.gpu.device, &mut self.renderer, &self.gpu.queue, &data);
+        if let Some(data) = self.data.server.send() {
+            self.link.push(&data);
+        }
+    }
+
+    /// Hands every byte that arrived since the last frame to the client
+    fn receive_packets(&mut self) {
+        loop {
+            match self.client_link.try_recv() {
+                Ok(Some(data)) => if let Err(e) = self.data.receive(&self.gpu.device, &mut self.renderer, &self.gpu.queue, &data) {
+                    eprintln!("Rejected packet: {e}")
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to receive packet: {e}");
+                    break
+                },
+            }
+        }
     }
 
     pub(crate) fn clear(&mut self) {
+        self.drop_in_flight();
+        self.autoplay.restart();
         self.data.server.clear();
-        self.data.client.clear(&self.gpu.device, &mut self.renderer, &self.gpu.queue);
+        self.data.client.clear();
+        self.data.update_client_texture(&self.gpu.device, &mut self.renderer, &self.gpu.queue);
+    }
+
+    /// Sends the image at `path` from now on. Failures are shown in the
+    /// sender window, keeping the current image
+    pub(crate) fn open_image(&mut self, path: PathBuf) {
+        match self.data.open_image(&path, &self.gpu.device, &mut self.renderer, &self.gpu.queue) {
+            Ok(()) => {
+                self.drop_in_flight();
+                self.autoplay.playing = false;
+                self.sender_ui.image_path = path.display().to_string();
+                self.sender_ui.image_error = None;
+            },
+            Err(e) => self.sender_ui.image_error = Some(format!("Couldn't open {}: {e}", path.display())),
+        }
+    }
+
+    /// Packets still in flight belong to the previous transmission. Stream
+    /// transports may hold some of them in the socket, where only opening a
+    /// new connection leaves them behind
+    fn drop_in_flight(&mut self) {
+        self.link.clear();
+        match self.transport.connect() {
+            Ok((server_link, client_link)) => {
+                self.server_link = server_link;
+                self.client_link = client_link;
+            },
+            Err(e) => {
+                eprintln!("Failed to reconnect, stale packets may arrive: {e}");
+                while let Ok(Some(_)) = self.client_link.try_recv() {}
+            },
+        }
     }
 }
 
@@ -171,33 +275,597 @@ fn render_state(view: &wgpu::TextureView, state: &mut TeState, gpu: &GpuState) {
     gpu.queue.submit(std::iter::once(encoder.finish()));
 }
 
-fn receiver_window(ui: &mut imgui::Ui, data: &mut ClientData, device: &Device, renderer: &mut Renderer, queue: &Queue) -> Option<ActionTaken> {
+struct ReceiverUi {
+    export_path: String,
+    export_status: Option<String>,
+    snapshot_path: String,
+    sequence_path: String,
+    sequence_format: SequenceFormat,
+    /// Time every stage is shown in animations
+    frame_delay_ms: u32,
+    /// Whether snapshots and sequences are blurred like the picture on screen
+    blur_exports: bool,
+}
+
+impl Default for ReceiverUi {
+    fn default() -> Self {
+        ReceiverUi {
+            export_path: "received.png".to_string(),
+            export_status: None,
+            snapshot_path: "snapshot.png".to_string(),
+            sequence_path: "stages.gif".to_string(),
+            sequence_format: SequenceFormat::default(),
+            frame_delay_ms: 500,
+            blur_exports: false,
+        }
+    }
+}
+
+fn receiver_window(ui: &mut imgui::Ui, data: &mut Decoder, texture_id: TextureId, receiver_ui: &mut ReceiverUi) -> Option<ActionTaken> {
     let mut action = None;
     ui.window("Receiver").build(|| {
-        imgui::Image::new(data.texture_id, data.size).border_col([1.0, 1.0, 1.0, 1.0]).build(ui);
+        let (width, height) = data.dimensions();
+        imgui::Image::new(texture_id, [width as f32, height as f32]).border_col([1.0, 1.0, 1.0, 1.0]).build(ui);
         if ui.button("Clear") {
             action = Some(ActionTaken::Clear)
         };
 
         if ui.checkbox("Blur", &mut data.blur) {
-            data.update_texture(device, renderer, queue)
+            data.redraw()
+        }
+        if data.blur && blur_controls(ui, &mut data.blur_kernel, &mut data.blur_known) {
+            data.redraw()
+        }
+        let mut selected = Reconstruction::ALL.iter().position(|&mode| mode == data.reconstruction).unwrap();
+        let names = Reconstruction::ALL.map(|mode| mode.name());
+        if ui.combo_simple_string("Missing pixels", &mut selected, &names) {
+            data.reconstruction = Reconstruction::ALL[selected];
+            data.redraw()
+        }
+
+        if data.layout().is_float() {
+            let mut changed = ui.slider("Exposure", -8.0, 8.0, &mut data.tone_mapping.exposure);
+            let mut selected = ToneOperator::ALL.iter().position(|&operator| operator == data.tone_mapping.operator).unwrap();
+            let names = ToneOperator::ALL.map(|operator| operator.name());
+            if ui.combo_simple_string("Tone mapping", &mut selected, &names) {
+                data.tone_mapping.operator = ToneOperator::ALL[selected];
+                changed = true
+            }
+            if changed {
+                data.redraw()
+            }
+        }
+
+        ui.text(format!("Received: {} bytes", data.bytes_received()));
+        if ui.collapsing_header("Quality", imgui::TreeNodeFlags::DEFAULT_OPEN) {
+            quality_table(ui, data);
+        }
+
+        ui.input_text("##export path", &mut receiver_ui.export_path).build();
+        ui.same_line();
+        ui.disabled(!data.is_complete(), || {
+            if ui.button("Save interlaced PNG") {
+                receiver_ui.export_status = Some(match data.export_png(&receiver_ui.export_path) {
+                    Ok(()) => format!("Saved {}", receiver_ui.export_path),
+                    Err(e) => format!("Couldn't save {}: {e}", receiver_ui.export_path),
+                })
+            }
+        });
+
+        if ui.collapsing_header("Snapshots", imgui::TreeNodeFlags::empty()) {
+            snapshot_settings(ui, data, receiver_ui);
+        }
+
+        if let Some(status) = &receiver_ui.export_status {
+            ui.text(status);
         }
     });
 
     action
 }
 
-fn sender_window(ui: &mut imgui::Ui, data: &ServerData) -> Option<ActionTaken> {
+/// Returns whether the kernel changed
+fn blur_controls(ui: &imgui::Ui, kernel: &mut BlurKernel, blur_known: &mut bool) -> bool {
+    let mut changed = ui.checkbox("Follow the stage", &mut kernel.auto);
+    if ui.is_item_hovered() {
+        ui.tooltip_text("Blur as far as the rows of the current stage are apart");
+    }
+    if !kernel.auto {
+        changed |= ui.slider("Radius", 0, 32, &mut kernel.radius);
+        changed |= ui.slider("Sigma", 0.1, 32.0, &mut kernel.sigma);
+    }
+    changed |= ui.checkbox("Blur received pixels", blur_known);
+    if ui.is_item_hovered() {
+        ui.tooltip_text("Otherwise only the pixels estimated from them are blurred");
+    }
+
+    changed
+}
+
+/// Quality of every stage against the source, raw and as blurred on screen
+fn quality_table(ui: &imgui::Ui, data: &mut Decoder) {
+    ui.checkbox("Measure quality", &mut data.measure_quality);
+    if data.metrics().is_empty() {
+        return
+    }
+
+    let psnr = |quality: &Quality| if quality.psnr.is_finite() {
+        format!("{:.2} dB", quality.psnr)
+    } else {
+        "lossless".to_string()
+    };
+    let headers = ["Stage", "Bytes", "MSE", "PSNR", "SSIM", "MSE (blur)", "PSNR (blur)", "SSIM (blur)"];
+    ui.columns(headers.len() as i32, "##quality", true);
+    for header in headers {
+        ui.text(header);
+        ui.next_column();
+    }
+    ui.separator();
+    for metrics in data.metrics() {
+        let cells = [
+            metrics.stage.to_string(),
+            metrics.bytes_received.to_string(),
+            format!("{:.2}", metrics.raw.mse),
+            psnr(&metrics.raw),
+            format!("{:.4}", metrics.raw.ssim),
+            format!("{:.2}", metrics.blurred.mse),
+            psnr(&metrics.blurred),
+            format!("{:.4}", metrics.blurred.ssim),
+        ];
+        for cell in cells {
+            ui.text(cell);
+            ui.next_column();
+        }
+    }
+    ui.columns(1, "##quality", false);
+}
+
+fn snapshot_settings(ui: &imgui::Ui, data: &mut Decoder, receiver_ui: &mut ReceiverUi) {
+    ui.checkbox("Blur exports", &mut receiver_ui.blur_exports);
+
+    ui.input_text("##snapshot path", &mut receiver_ui.snapshot_path).build();
+    ui.same_line();
+    if ui.button("Save snapshot") {
+        receiver_ui.export_status = Some(match data.save_snapshot(&receiver_ui.snapshot_path, receiver_ui.blur_exports) {
+            Ok(()) => format!("Saved {}", receiver_ui.snapshot_path),
+            Err(e) => format!("Couldn't save {}: {e}", receiver_ui.snapshot_path),
+        })
+    }
+
+    ui.separator();
+    ui.checkbox("Record stages", &mut data.record_stages);
+    let mut selected = SequenceFormat::ALL.iter().position(|&format| format == receiver_ui.sequence_format).unwrap();
+    let names = SequenceFormat::ALL.map(|format| format.name());
+    if ui.combo_simple_string("Sequence format", &mut selected, &names) {
+        receiver_ui.sequence_format = SequenceFormat::ALL[selected];
+        let path = Path::new(&receiver_ui.sequence_path).with_extension(receiver_ui.sequence_format.extension());
+        receiver_ui.sequence_path = path.display().to_string();
+    }
+    if receiver_ui.sequence_format != SequenceFormat::PngSeries {
+        ui.slider("Stage duration (ms)", 20, 5000, &mut receiver_ui.frame_delay_ms);
+    }
+    ui.input_text("##sequence path", &mut receiver_ui.sequence_path).build();
+    ui.same_line();
+    let stages = data.recorded_stages();
+    ui.disabled(stages == 0, || {
+        if ui.button(format!("Save {stages} stages")) {
+            let delay = Duration::from_millis(receiver_ui.frame_delay_ms as u64);
+            let result = data.save_sequence(&receiver_ui.sequence_path, receiver_ui.sequence_format, delay, receiver_ui.blur_exports);
+            receiver_ui.export_status = Some(match result {
+                Ok(()) => format!("Saved {}", receiver_ui.sequence_path),
+                Err(e) => format!("Couldn't save {}: {e}", receiver_ui.sequence_path),
+            })
+        }
+    });
+}
+
+fn sender_window(ui: &mut imgui::Ui, data: &mut Encoder, texture_id: TextureId, autoplay: &mut Autoplay, link: &mut LinkSimulator, sender_ui: &mut SenderUi) -> Option<ActionTaken> {
     let mut action = None;
     ui.window("Sender").build(|| {
-        let size = [data.image_size[0] / 4.0, data.image_size[1] / 4.0];
-        imgui::Image::new(data.texture_id, size).build(ui);
-        ui.disabled(data.send_stage == SendStage::End, || {
+        let (width, height) = data.dimensions();
+        let size = [width as f32 / 4.0, height as f32 / 4.0];
+        imgui::Image::new(texture_id, size).build(ui);
+        let layout = data.layout();
+        ui.text(format!("Pixels: {:?}, {} bytes each", layout, layout.bytes_per_pixel()));
+        ui.disabled(data.is_complete(), || {
             if ui.button("Send") {
                 action = Some(ActionTaken::Send)
             }
-        })
+            ui.same_line();
+            let label = if autoplay.playing {
+                "Pause"
+            } else {
+                "Play"
+            };
+            if ui.button(label) {
+                autoplay.toggle()
+            }
+        });
+        ui.slider_config("Stages per second", 0.1, 30.0)
+            .flags(imgui::SliderFlags::LOGARITHMIC)
+            .display_format("%.1f")
+            .build(&mut autoplay.rate);
+        ui.checkbox("Wait for the link", &mut autoplay.wait_for_link);
+
+        if ui.collapsing_header("Image", imgui::TreeNodeFlags::empty()) {
+            if let Some(path) = image_settings(ui, sender_ui) {
+                action = Some(ActionTaken::Open(path))
+            }
+        }
+
+        if ui.collapsing_header("Stages", imgui::TreeNodeFlags::DEFAULT_OPEN) {
+            stage_settings(ui, data, sender_ui);
+        }
+
+        if ui.collapsing_header("Link", imgui::TreeNodeFlags::DEFAULT_OPEN) {
+            link_settings(ui, link, sender_ui);
+        }
     });
 
     action
 }
+
+/// Sends stages on its own, paced by the frame clock
+struct Autoplay {
+    playing: bool,
+    /// Stages per second
+    rate: f32,
+    /// Don't send a stage until the previous one has gone through the link
+    wait_for_link: bool,
+    /// Time since the last stage was sent
+    elapsed: Duration,
+}
+
+impl Default for Autoplay {
+    fn default() -> Self {
+        Autoplay {
+            playing: false,
+            rate: 2.0,
+            wait_for_link: true,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl Autoplay {
+    fn toggle(&mut self) {
+        self.playing = !self.playing;
+        // Start with a stage instead of waiting a whole period
+        self.elapsed = self.period();
+    }
+
+    fn restart(&mut self) {
+        self.elapsed = self.period();
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.rate.max(0.01))
+    }
+
+    /// Advances the clock by `dt` and returns how many stages are due
+    fn tick(&mut self, dt: Duration, link_idle: bool) -> u32 {
+        if !self.playing {
+            return 0
+        }
+        self.elapsed += dt;
+        if self.wait_for_link && !link_idle {
+            // Don't let the time spent waiting pile up
+            self.elapsed = self.elapsed.min(self.period());
+            return 0
+        }
+
+        let period = self.period();
+        let mut due = 0;
+        while self.elapsed >= period {
+            self.elapsed -= period;
+            due += 1;
+            if self.wait_for_link {
+                self.elapsed = Duration::ZERO;
+                break
+            }
+        }
+
+        due
+    }
+}
+
+struct SenderUi {
+    /// Ladder of the block scheme, remembered while other schemes are selected
+    ladder: Ladder,
+    first_stage_pixels: i32,
+    /// Quality of the DCT scheme, remembered while other schemes are selected
+    quality: u8,
+    /// Levels of the wavelet scheme, remembered while other schemes are selected
+    levels: u8,
+    /// Bits per stage of the bit plane scheme, remembered while other schemes are selected
+    bits_per_stage: u8,
+    preset: usize,
+    trace_path: String,
+    trace_error: Option<String>,
+    /// Image to open, also where the browser looks
+    image_path: String,
+    image_error: Option<String>,
+    /// Contents of the folder the browser shows, read once per folder
+    listing: Option<Listing>,
+}
+
+impl Default for SenderUi {
+    fn default() -> Self {
+        SenderUi {
+            ladder: Ladder::default(),
+            first_stage_pixels: 256,
+            quality: 75,
+            levels: 5,
+            bits_per_stage: 1,
+            preset: 0,
+            trace_path: String::new(),
+            trace_error: None,
+            image_path: String::new(),
+            image_error: None,
+            listing: None,
+        }
+    }
+}
+
+/// Subfolders and images of a folder, sorted by name
+struct Listing {
+    folder: PathBuf,
+    folders: Vec<String>,
+    images: Vec<String>,
+}
+
+impl Listing {
+    fn read(folder: PathBuf) -> Listing {
+        let mut folders = Vec::new();
+        let mut images = Vec::new();
+        for entry in std::fs::read_dir(&folder).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_dir() {
+                folders.push(name)
+            } else if image::ImageFormat::from_path(entry.path()).is_ok() {
+                images.push(name)
+            }
+        }
+        folders.sort();
+        images.sort();
+
+        Listing {
+            folder,
+            folders,
+            images,
+        }
+    }
+}
+
+/// Path field and a browser of the folder it points to. Returns the image
+/// to open, if any
+fn image_settings(ui: &imgui::Ui, sender_ui: &mut SenderUi) -> Option<PathBuf> {
+    let mut open = None;
+    ui.text("Drop an image on the window, or pick one:");
+    let entered = ui.input_text("##image path", &mut sender_ui.image_path)
+        .enter_returns_true(true)
+        .build();
+    ui.same_line();
+    if ui.button("Open") || entered {
+        open = Some(PathBuf::from(&sender_ui.image_path))
+    }
+    if let Some(error) = &sender_ui.image_error {
+        ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+    }
+
+    let path = Path::new(&sender_ui.image_path);
+    let folder = if path.is_dir() {
+        path.to_path_buf()
+    } else {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    };
+    if sender_ui.listing.as_ref().map(|listing| &listing.folder) != Some(&folder) {
+        sender_ui.listing = Some(Listing::read(folder))
+    }
+    let listing = sender_ui.listing.as_ref().unwrap();
+    let mut picked = None;
+    ui.child_window("##browser").size([0.0, 150.0]).border(true).build(|| {
+        if ui.selectable("../") {
+            picked = Some(listing.folder.join(".."))
+        }
+        for name in listing.folders.iter() {
+            if ui.selectable(format!("{name}/")) {
+                picked = Some(listing.folder.join(name))
+            }
+        }
+        for name in listing.images.iter() {
+            if ui.selectable(name) {
+                open = Some(listing.folder.join(name))
+            }
+        }
+    });
+    if let Some(folder) = picked {
+        sender_ui.image_path = format!("{}{}", folder.display(), std::path::MAIN_SEPARATOR);
+    }
+
+    open
+}
+
+fn stage_settings(ui: &imgui::Ui, data: &mut Encoder, sender_ui: &mut SenderUi) {
+    let scheme = data.scheme();
+    // Changing the scheme halfway through would leave the client with a mix of both
+    ui.disabled(data.stage() != 0, || {
+        let schemes = [
+            Scheme::Blocks(sender_ui.ladder),
+            Scheme::Adam7,
+            Scheme::Dct { quality: sender_ui.quality },
+            Scheme::Wavelet { levels: sender_ui.levels },
+            Scheme::BitPlanes { bits_per_stage: sender_ui.bits_per_stage },
+        ];
+        let names: Vec<_> = schemes.iter().map(|scheme| scheme.name()).collect();
+        let mut selected = schemes.iter().position(|s| s.name() == scheme.name()).unwrap_or(0);
+        if ui.combo_simple_string("Scheme", &mut selected, &names) {
+            data.set_scheme(schemes[selected])
+        }
+
+        if let Scheme::Blocks(ladder) = scheme {
+            let mut new_ladder = None;
+            let sizes: Vec<_> = (0..=Ladder::MAX_COARSEST).map(|log2| format!("{0}x{0}", 1u32 << log2)).collect();
+            let mut coarsest = ladder.coarsest() as usize;
+            if ui.combo_simple_string("Coarsest block", &mut coarsest, &sizes) {
+                new_ladder = Some(Ladder::new(coarsest as u8, coarsest as u8 + 1))
+            }
+            let mut stages = ladder.stages();
+            if ui.slider("Stages", 1, ladder.coarsest() + 1, &mut stages) {
+                new_ladder = Some(Ladder::new(ladder.coarsest(), stages))
+            }
+            ui.input_int("First stage pixels", &mut sender_ui.first_stage_pixels).build();
+            if ui.button("Fit to image") {
+                let (width, height) = data.dimensions();
+                let target = sender_ui.first_stage_pixels.max(1) as u64;
+                new_ladder = Some(Ladder::for_image(width, height, target))
+            }
+
+            if let Some(ladder) = new_ladder {
+                sender_ui.ladder = ladder;
+                data.set_scheme(Scheme::Blocks(ladder))
+            }
+        }
+
+        if let Scheme::Dct { mut quality } = scheme {
+            if ui.slider("Quality", 1, 100, &mut quality) {
+                sender_ui.quality = quality;
+                data.set_scheme(Scheme::Dct { quality })
+            }
+        }
+
+        if let Scheme::Wavelet { mut levels } = scheme {
+            if ui.slider("Levels", 1, Scheme::MAX_WAVELET_LEVELS, &mut levels) {
+                sender_ui.levels = levels;
+                data.set_scheme(Scheme::Wavelet { levels })
+            }
+        }
+
+        if let Scheme::BitPlanes { mut bits_per_stage } = scheme {
+            if ui.slider("Bits per stage", 1, 8, &mut bits_per_stage) {
+                sender_ui.bits_per_stage = bits_per_stage;
+                data.set_scheme(Scheme::BitPlanes { bits_per_stage })
+            }
+        }
+
+        let layout = data.layout();
+        let has_color = layout.rgb_channels().is_some() && !layout.is_float();
+        if matches!(scheme, Scheme::Blocks(_) | Scheme::Adam7) && has_color {
+            let color = data.color();
+            let mut selected = match color {
+                ColorTransform::None => 0,
+                ColorTransform::YCoCgR { .. } => 1,
+            };
+            if ui.combo_simple_string("Color", &mut selected, &["RGB", "YCoCg-R"]) {
+                data.set_color(match selected {
+                    0 => ColorTransform::None,
+                    _ => ColorTransform::YCoCgR { chroma_lag: 1 },
+                })
+            }
+            if let ColorTransform::YCoCgR { mut chroma_lag } = color {
+                if ui.slider("Chroma lag", 0, ColorTransform::MAX_CHROMA_LAG, &mut chroma_lag) {
+                    data.set_color(ColorTransform::YCoCgR { chroma_lag })
+                }
+            }
+        }
+    });
+
+    match scheme {
+        Scheme::Blocks(ladder) => {
+            let sizes: Vec<_> = ladder.block_sizes().map(|size| size.to_string()).collect();
+            ui.text(format!("Blocks: {}", sizes.join(", ")));
+        },
+        Scheme::Adam7 => if data.has_png_passes() {
+            ui.text("Passes are sent as stored in the PNG, without residuals or a color transform");
+        },
+        Scheme::Dct { .. } => {
+            ui.text("Scans: DC, AC 1-5, AC 6-63, then the last bits of every coefficient");
+        },
+        Scheme::Wavelet { levels } => {
+            let (width, height) = data.dimensions();
+            let shrink = 1u64 << levels;
+            ui.text(format!(
+                "Lowest resolution: {}x{}",
+                (width as u64).div_ceil(shrink),
+                (height as u64).div_ceil(shrink),
+            ));
+        },
+        Scheme::BitPlanes { .. } => {
+            ui.text(format!("Stages: {}", scheme.stages()));
+        },
+    }
+
+    if let Scheme::Blocks(_) | Scheme::Adam7 = scheme {
+        ui.checkbox("Residual prediction", &mut data.residual_prediction);
+    }
+    ui.checkbox("Entropy coding", &mut data.entropy_coding);
+    if let Some((sent, raw)) = data.last_payload() {
+        ui.text(format!("Last stage: {sent} bytes ({raw} before coding)"));
+    }
+}
+
+fn link_settings(ui: &imgui::Ui, link: &mut LinkSimulator, sender_ui: &mut SenderUi) {
+    let names: Vec<_> = PRESETS.iter().map(|(name, _)| *name).collect();
+    if ui.combo_simple_string("Profile", &mut sender_ui.preset, &names) {
+        link.profile = PRESETS[sender_ui.preset].1.clone();
+    }
+
+    match &link.profile.bandwidth {
+        Bandwidth::Unlimited => {
+            ui.text("Bandwidth: unlimited");
+            ui.same_line();
+            if ui.small_button("Limit") {
+                link.profile.bandwidth = Bandwidth::Constant(1_000_000.0)
+            }
+        },
+        Bandwidth::Constant(rate) => {
+            let mut kbps = rate / 1000.0;
+            if ui.slider_config("Bandwidth (kbit/s)", 8.0, 1_000_000.0)
+                .flags(imgui::SliderFlags::LOGARITHMIC)
+                .display_format("%.0f")
+                .build(&mut kbps)
+            {
+                link.profile.bandwidth = Bandwidth::Constant(kbps * 1000.0)
+            }
+        },
+        Bandwidth::Trace(_) => {
+            ui.text("Bandwidth: trace");
+            ui.same_line();
+            if ui.small_button("Constant") {
+                link.profile.bandwidth = Bandwidth::Constant(1_000_000.0)
+            }
+        },
+    }
+
+    let mut rtt = link.profile.rtt * 1000.0;
+    if ui.slider_config("RTT (ms)", 0.0, 2000.0).display_format("%.0f").build(&mut rtt) {
+        link.profile.rtt = rtt / 1000.0
+    }
+    let mut jitter = link.profile.jitter * 1000.0;
+    if ui.slider_config("Jitter (ms)", 0.0, 500.0).display_format("%.0f").build(&mut jitter) {
+        link.profile.jitter = jitter / 1000.0
+    }
+    let mut loss = link.profile.loss * 100.0;
+    if ui.slider_config("Loss (%)", 0.0, 50.0).display_format("%.1f").build(&mut loss) {
+        link.profile.loss = loss / 100.0
+    }
+
+    ui.input_text("Trace CSV", &mut sender_ui.trace_path).build();
+    if ui.button("Load trace") {
+        match ThroughputTrace::load(&sender_ui.trace_path) {
+            Ok(trace) => {
+                link.profile.bandwidth = Bandwidth::Trace(trace);
+                sender_ui.trace_error = None;
+            },
+            Err(e) => sender_ui.trace_error = Some(e.to_string()),
+        }
+    }
+    if let Some(error) = &sender_ui.trace_error {
+        ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+    }
+
+    ui.text(format!("In flight: {} bytes", link.pending()));
+}
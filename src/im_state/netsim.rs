@@ -0,0 +1,437 @@
+//! Simulated network link between the server and the transport.
+//!
+//! Bytes pushed into the [LinkSimulator] are cut into segments which are
+//! serialized at the link's bandwidth, delayed by half the round trip time
+//! (plus jitter) and delivered in order, like a TCP connection would.
+//! A lost segment is noticed by the sender one round trip later and sent again.
+//!
+//! Time only advances when [LinkSimulator::advance] is called, so the link runs
+//! on the frame clock instead of the wall clock.
+use std::{collections::VecDeque, fmt, io, path::Path, time::Duration};
+
+/// Maximum segment size, in bytes
+const MSS: usize = 1460;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkProfile {
+    pub bandwidth: Bandwidth,
+    /// Round trip time, in seconds
+    pub rtt: f64,
+    /// Maximum deviation of the one-way delay, in seconds
+    pub jitter: f64,
+    /// Probability of a segment being lost, from 0 to 1
+    pub loss: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bandwidth {
+    Unlimited,
+    /// Bits per second
+    Constant(f64),
+    Trace(ThroughputTrace),
+}
+
+impl LinkProfile {
+    pub const fn instant() -> LinkProfile {
+        LinkProfile {
+            bandwidth: Bandwidth::Unlimited,
+            rtt: 0.0,
+            jitter: 0.0,
+            loss: 0.0,
+        }
+    }
+
+    const fn constant(kbps: f64, rtt_ms: f64, jitter_ms: f64, loss: f64) -> LinkProfile {
+        LinkProfile {
+            bandwidth: Bandwidth::Constant(kbps * 1000.0),
+            rtt: rtt_ms / 1000.0,
+            jitter: jitter_ms / 1000.0,
+            loss,
+        }
+    }
+
+    fn is_instant(&self) -> bool {
+        self.bandwidth == Bandwidth::Unlimited && self.rtt <= 0.0 && self.jitter <= 0.0
+    }
+}
+
+pub const PRESETS: &[(&str, LinkProfile)] = &[
+    ("Instant", LinkProfile::instant()),
+    ("Fiber", LinkProfile::constant(100_000.0, 10.0, 1.0, 0.0)),
+    ("DSL", LinkProfile::constant(8_000.0, 40.0, 5.0, 0.001)),
+    ("4G", LinkProfile::constant(12_000.0, 60.0, 15.0, 0.005)),
+    ("3G", LinkProfile::constant(1_500.0, 150.0, 30.0, 0.01)),
+    ("2G", LinkProfile::constant(200.0, 500.0, 100.0, 0.02)),
+    ("Dial-up", LinkProfile::constant(56.0, 200.0, 20.0, 0.0)),
+];
+
+/// Throughput over time, played in a loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThroughputTrace {
+    /// Start of each piece, in seconds. The first one is always 0
+    times: Vec<f64>,
+    /// Throughput of each piece, in bits per second
+    rates: Vec<f64>,
+    /// Length of the whole trace, in seconds
+    period: f64,
+}
+
+impl ThroughputTrace {
+    /// Reads a CSV file with a `time_seconds,throughput_kbps` row per sample.
+    /// Each throughput holds until the time of the next row. A header row and
+    /// lines starting with `#` are ignored.
+    pub fn load(path: impl AsRef<Path>) -> Result<ThroughputTrace, TraceError> {
+        let contents = std::fs::read_to_string(path)?;
+        ThroughputTrace::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<ThroughputTrace, TraceError> {
+        let mut samples: Vec<(f64, f64)> = Vec::new();
+        let mut first_row = true;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let is_first_row = first_row;
+            first_row = false;
+            let mut fields = line.split(',').map(str::trim);
+            let time = fields.next().and_then(|f| f.parse::<f64>().ok());
+            let kbps = fields.next().and_then(|f| f.parse::<f64>().ok());
+            match (time, kbps) {
+                (Some(time), Some(kbps)) if time.is_finite() && kbps.is_finite() && kbps >= 0.0 => {
+                    if let Some((last, _)) = samples.last() {
+                        if time <= *last {
+                            return Err(TraceError::Parse { line: i + 1, message: "times must be increasing".into() })
+                        }
+                    }
+                    samples.push((time, kbps * 1000.0))
+                },
+                // Header row
+                _ if is_first_row => (),
+                _ => return Err(TraceError::Parse { line: i + 1, message: format!("expected `time,kbps`, got `{line}`") }),
+            }
+        }
+
+        if !samples.iter().any(|(_, rate)| *rate > 0.0) {
+            return Err(TraceError::NoThroughput)
+        }
+
+        let first = samples[0].0;
+        let times: Vec<_> = samples.iter().map(|(time, _)| time - first).collect();
+        let rates: Vec<_> = samples.iter().map(|(_, rate)| *rate).collect();
+        // The last sample lasts as long as the one before it
+        let last_len = match times.len() {
+            1 => 1.0,
+            n => times[n - 1] - times[n - 2],
+        };
+        let period = times[times.len() - 1] + last_len;
+
+        Ok(ThroughputTrace {
+            times,
+            rates,
+            period,
+        })
+    }
+
+    /// Piece playing `local` seconds into the trace
+    fn piece_at(&self, local: f64) -> usize {
+        self.times.partition_point(|start| *start <= local).max(1) - 1
+    }
+
+    /// Time at which `piece` ends, from the start of the trace
+    fn piece_end(&self, piece: usize) -> f64 {
+        self.times.get(piece + 1).copied().unwrap_or(self.period)
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    NoThroughput,
+}
+
+impl From<io::Error> for TraceError {
+    fn from(value: io::Error) -> Self {
+        TraceError::Io(value)
+    }
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(e) => write!(f, "{e}"),
+            TraceError::Parse { line, message } => write!(f, "line {line}: {message}"),
+            TraceError::NoThroughput => write!(f, "the trace never has any throughput"),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+struct Segment {
+    data: Vec<u8>,
+    /// Time at which the segment was handed to the link
+    queued_at: f64,
+}
+
+pub struct LinkSimulator {
+    pub profile: LinkProfile,
+    /// Simulated time, in seconds
+    clock: f64,
+    /// Segments waiting for the link to be free
+    queued: VecDeque<Segment>,
+    /// Segments on the wire, with their arrival time
+    in_flight: VecDeque<(f64, Vec<u8>)>,
+    /// Time at which the link finishes serializing the last segment
+    busy_until: f64,
+    rng: XorShift,
+}
+
+impl LinkSimulator {
+    pub fn new(profile: LinkProfile) -> LinkSimulator {
+        LinkSimulator {
+            profile,
+            clock: 0.0,
+            queued: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            busy_until: 0.0,
+            rng: XorShift::new(0x9E37_79B9_7F4A_7C15),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(MSS) {
+            self.queued.push_back(Segment {
+                data: chunk.to_vec(),
+                queued_at: self.clock,
+            })
+        }
+    }
+
+    /// Advances the simulated time by `dt` and returns every byte that
+    /// arrived at the other end meanwhile.
+    pub fn advance(&mut self, dt: Duration) -> Vec<u8> {
+        self.clock += dt.as_secs_f64();
+        if self.profile.is_instant() {
+            let mut delivered: Vec<u8> = self.in_flight.drain(..).flat_map(|(_, data)| data).collect();
+            delivered.extend(self.queued.drain(..).flat_map(|segment| segment.data));
+            return delivered
+        }
+
+        while let Some(segment) = self.queued.front() {
+            let start = self.busy_until.max(segment.queued_at);
+            let sent = self.transmission_end(start, segment.data.len());
+            if sent > self.clock {
+                break
+            }
+            self.busy_until = sent;
+            let arrival = self.arrival(sent, segment.data.len());
+            let segment = self.queued.pop_front().unwrap();
+            self.in_flight.push_back((arrival, segment.data));
+        }
+
+        let mut delivered = Vec::new();
+        while let Some((arrival, _)) = self.in_flight.front() {
+            if *arrival > self.clock {
+                break
+            }
+            delivered.extend(self.in_flight.pop_front().unwrap().1);
+        }
+
+        delivered
+    }
+
+    /// Bytes that were pushed but haven't arrived yet
+    pub fn pending(&self) -> usize {
+        self.queued.iter().map(|segment| segment.data.len()).sum::<usize>()
+        + self.in_flight.iter().map(|(_, data)| data.len()).sum::<usize>()
+    }
+
+    /// Drops everything on the link
+    pub fn clear(&mut self) {
+        self.queued.clear();
+        self.in_flight.clear();
+        self.busy_until = self.clock;
+    }
+
+    fn transmission_end(&self, start: f64, len: usize) -> f64 {
+        let bits = (len * 8) as f64;
+        match &self.profile.bandwidth {
+            Bandwidth::Unlimited => start,
+            Bandwidth::Constant(rate) => start + bits / rate.max(1.0),
+            Bandwidth::Trace(trace) => {
+                let mut bits = bits;
+                let mut t = start;
+                let mut period_start = start - start.rem_euclid(trace.period);
+                let mut piece = trace.piece_at(start - period_start);
+                loop {
+                    // Rounding may put the end of the piece at `t` or before
+                    // it, which is then skipped
+                    let piece_end = period_start + trace.piece_end(piece);
+                    let rate = trace.rates[piece];
+                    if rate > 0.0 && piece_end > t {
+                        let needed = bits / rate;
+                        if t + needed <= piece_end {
+                            return t + needed
+                        }
+                        bits -= rate * (piece_end - t);
+                    }
+                    t = t.max(piece_end);
+                    piece += 1;
+                    if piece == trace.times.len() {
+                        piece = 0;
+                        period_start += trace.period;
+                    }
+                }
+            },
+        }
+    }
+
+    /// Time at which a segment of `len` bytes whose serialization ends at
+    /// `sent` arrives. The link is taken again every time it is lost
+    fn arrival(&mut self, sent: f64, len: usize) -> f64 {
+        let loss = self.profile.loss.clamp(0.0, 0.99);
+        let mut sent = sent;
+        while self.rng.next_f64() < loss {
+            // The sender notices after a round trip and sends it again,
+            // keeping the link busy as long as the first time
+            let resent = (sent + self.profile.rtt).max(self.busy_until);
+            sent = self.transmission_end(resent, len);
+            self.busy_until = sent;
+        }
+
+        let jitter = (self.rng.next_f64() * 2.0 - 1.0) * self.profile.jitter;
+        let arrival = sent + (self.profile.rtt / 2.0 + jitter).max(0.0);
+        // Segments are delivered in order
+        match self.in_flight.back() {
+            Some((last, _)) => arrival.max(*last),
+            None => arrival,
+        }
+    }
+}
+
+/// Small deterministic generator so runs are reproducible
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        XorShift(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 8 kb/s for a second, nothing for a second, then 16 kb/s for a second
+    const TRACE: &str = "time_seconds,throughput_kbps\n# measured on a train\n0,8\n1,0\n\n2,16\n";
+
+    fn trace_link() -> LinkSimulator {
+        LinkSimulator::new(LinkProfile {
+            bandwidth: Bandwidth::Trace(ThroughputTrace::parse(TRACE).unwrap()),
+            ..LinkProfile::instant()
+        })
+    }
+
+    #[test]
+    fn parses_a_trace() {
+        let trace = ThroughputTrace::parse(TRACE).unwrap();
+        assert_eq!(trace.times, [0.0, 1.0, 2.0]);
+        assert_eq!(trace.rates, [8000.0, 0.0, 16000.0]);
+        assert_eq!(trace.period, 3.0);
+
+        // Times are counted from the first sample
+        let trace = ThroughputTrace::parse("10,5\n10.5,7").unwrap();
+        assert_eq!(trace.times, [0.0, 0.5]);
+        assert_eq!(trace.period, 1.0);
+    }
+
+    #[test]
+    fn bad_lines_name_their_line() {
+        for (contents, line) in [
+            ("time,kbps\n0,8\n1,fast\n", 3),
+            ("0,8\n# skipped\n\n2,8\n1,8\n", 5),
+            ("0,8\n0,8\n", 2),
+            ("0,8\n1,-8\n", 2),
+            ("0,8\n1\n", 2),
+        ] {
+            match ThroughputTrace::parse(contents) {
+                Err(TraceError::Parse { line: got, .. }) => assert_eq!(got, line, "{contents:?}"),
+                other => panic!("{contents:?}: expected a parse error, got {other:?}"),
+            }
+        }
+        assert!(matches!(ThroughputTrace::parse("0,0\n1,0\n"), Err(TraceError::NoThroughput)));
+        assert!(matches!(ThroughputTrace::parse("time,kbps\n"), Err(TraceError::NoThroughput)));
+    }
+
+    #[test]
+    fn transmission_spans_the_trace() {
+        let link = trace_link();
+        // Within the first piece
+        assert_eq!(link.transmission_end(0.25, 500), 0.75);
+        // 4 kb before the pause, the 12 kb left after it
+        assert_eq!(link.transmission_end(0.5, 2000), 2.75);
+        // 20 kb until the end of the period, the 4 kb left at the start of the next one
+        assert_eq!(link.transmission_end(0.5, 3000), 3.5);
+        // Starting in a later period, in the pause
+        assert_eq!(link.transmission_end(31.5, 1000), 32.5);
+    }
+
+    #[test]
+    fn transmission_ends_from_every_piece_boundary() {
+        let link = LinkSimulator::new(LinkProfile {
+            bandwidth: Bandwidth::Trace(ThroughputTrace::parse("0,8\n0.1,0\n0.3,16\n").unwrap()),
+            ..LinkProfile::instant()
+        });
+        for i in 0..10_000 {
+            let start = i as f64 * 0.1;
+            let end = link.transmission_end(start, 10);
+            assert!(end > start, "{start}: ends at {end}");
+        }
+    }
+
+    #[test]
+    fn lost_segments_are_sent_again() {
+        // A segment takes a second to serialize
+        let rate = (MSS * 8) as f64;
+        let mut link = LinkSimulator::new(LinkProfile {
+            bandwidth: Bandwidth::Constant(rate),
+            rtt: 0.5,
+            jitter: 0.0,
+            loss: 0.0,
+        });
+        link.busy_until = 1.0;
+        assert_eq!(link.arrival(1.0, MSS), 1.25);
+        assert_eq!(link.busy_until, 1.0);
+
+        link.profile.loss = 0.99;
+        let arrival = link.arrival(1.0, MSS);
+        // Every resend waits a round trip and takes the link for a second
+        let resends = (link.busy_until - 1.0) / 1.5;
+        assert!(resends >= 1.0 && resends.fract() == 0.0, "busy until {}", link.busy_until);
+        assert_eq!(arrival, link.busy_until + 0.25);
+
+        // The next segment waits for the last resend
+        let busy_until = link.busy_until;
+        link.profile.loss = 0.0;
+        link.push(&[0; MSS]);
+        link.advance(Duration::from_secs_f64(busy_until + 1.5));
+        assert_eq!(link.busy_until, busy_until + 1.0);
+    }
+}
@@ -0,0 +1,246 @@
+//! Moves packets from the server to the client.
+//!
+//! Every backend hands out two connected endpoints. Sending is blocking,
+//! receiving never is: stream based backends read on a background thread
+//! and queue complete frames until [Transport::try_recv] picks them up.
+use std::{io::{self, Read, Write}, net::{Shutdown, TcpListener, TcpStream}, sync::mpsc::{self, Receiver, Sender, TryRecvError}, thread};
+
+pub trait Transport {
+    /// Delivers `frame` to the other endpoint as a single unit.
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+    /// Returns the next frame sent by the other endpoint, or `None` if
+    /// nothing has arrived yet.
+    fn try_recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// In-process channel, no sockets involved
+    Channel,
+    /// TCP connection over the loopback interface
+    Tcp,
+    /// Unix domain socket
+    #[cfg(unix)]
+    Unix,
+}
+
+impl TransportKind {
+    pub const ALL: &'static [TransportKind] = &[
+        TransportKind::Channel,
+        TransportKind::Tcp,
+        #[cfg(unix)]
+        TransportKind::Unix,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransportKind::Channel => "channel",
+            TransportKind::Tcp => "tcp",
+            #[cfg(unix)]
+            TransportKind::Unix => "unix",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TransportKind> {
+        TransportKind::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    /// Opens a connection and returns the (server, client) endpoints
+    pub fn connect(&self) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+        match self {
+            TransportKind::Channel => {
+                let (server, client) = ChannelTransport::pair();
+                Ok((Box::new(server), Box::new(client)))
+            },
+            TransportKind::Tcp => {
+                let listener = TcpListener::bind(("127.0.0.1", 0))?;
+                let client = TcpStream::connect(listener.local_addr()?)?;
+                let (server, _) = listener.accept()?;
+                server.set_nodelay(true)?;
+                client.set_nodelay(true)?;
+                Ok((Box::new(StreamTransport::new(server)?), Box::new(StreamTransport::new(client)?)))
+            },
+            #[cfg(unix)]
+            TransportKind::Unix => {
+                use std::os::unix::net::{UnixListener, UnixStream};
+
+                let path = std::env::temp_dir().join(format!("progressive-loading-{}.sock", std::process::id()));
+                // A previous run may have left its socket behind
+                let _ = std::fs::remove_file(&path);
+                let listener = UnixListener::bind(&path)?;
+                let client = UnixStream::connect(&path)?;
+                let (server, _) = listener.accept()?;
+                std::fs::remove_file(&path)?;
+                Ok((Box::new(StreamTransport::new(server)?), Box::new(StreamTransport::new(client)?)))
+            },
+        }
+    }
+}
+
+pub struct ChannelTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+
+        (
+            ChannelTransport { tx: a_tx, rx: a_rx },
+            ChannelTransport { tx: b_tx, rx: b_rx }
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx.send(frame.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.try_recv() {
+            Ok(frame) => Ok(Some(frame)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+/// Byte streams a [StreamTransport] runs over
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    /// Another handle of the same stream
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Closes both directions of the stream, for every handle of it
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Frames are sent over a byte stream prefixed by their length as a little
+/// endian `u32`.
+pub struct StreamTransport<S: Stream> {
+    stream: S,
+    frames: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl<S: Stream> StreamTransport<S> {
+    pub fn new(stream: S) -> io::Result<StreamTransport<S>> {
+        let mut reader = stream.try_clone()?;
+        let (tx, frames) = mpsc::channel();
+        thread::spawn(move || loop {
+            let frame = read_frame(&mut reader);
+            let failed = frame.is_err();
+            if tx.send(frame).is_err() || failed {
+                break
+            }
+        });
+
+        Ok(StreamTransport {
+            stream,
+            frames
+        })
+    }
+}
+
+impl<S: Stream> Drop for StreamTransport<S> {
+    /// The reader threads of both endpoints would wait on the stream forever
+    /// otherwise, since each of them holds a handle of it
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown();
+    }
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut frame = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut frame)?;
+
+    Ok(frame)
+}
+
+impl<S: Stream> Transport for StreamTransport<S> {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+        self.stream.write_all(&len.to_le_bytes())?;
+        self.stream.write_all(frame)?;
+        self.stream.flush()
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.frames.try_recv() {
+            Ok(frame) => frame.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// Next frame to arrive within `timeout`
+    fn recv(endpoint: &mut dyn Transport, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        loop {
+            match endpoint.try_recv()? {
+                Some(frame) => return Ok(Some(frame)),
+                None if start.elapsed() > timeout => return Ok(None),
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+    }
+
+    /// Frames sent before connecting again never reach the new client
+    #[test]
+    fn reconnecting_leaves_stale_frames_behind() {
+        for kind in TransportKind::ALL {
+            let (mut server, mut client) = kind.connect().unwrap();
+            server.send(b"first").unwrap();
+            assert_eq!(recv(&mut *client, Duration::from_secs(1)).unwrap().as_deref(), Some(&b"first"[..]), "{kind:?}");
+            server.send(b"stale").unwrap();
+
+            (server, client) = kind.connect().unwrap();
+            server.send(b"fresh").unwrap();
+            assert_eq!(recv(&mut *client, Duration::from_secs(1)).unwrap().as_deref(), Some(&b"fresh"[..]), "{kind:?}");
+            assert_eq!(recv(&mut *client, Duration::from_millis(100)).unwrap(), None, "{kind:?}");
+        }
+    }
+
+    /// Dropping an endpoint closes the connection, so the reader of the
+    /// other one stops too
+    #[test]
+    fn dropping_an_endpoint_disconnects() {
+        for kind in TransportKind::ALL {
+            let (server, mut client) = kind.connect().unwrap();
+            drop(server);
+            assert!(recv(&mut *client, Duration::from_secs(1)).is_err(), "{kind:?}");
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use im_state::ImState;
+use std::path::PathBuf;
+
+use im_state::{ImState, TransportKind};
 use te_renderer::initial_config::InitialConfiguration;
 use winit::{event_loop::{EventLoop, ControlFlow}, window::WindowBuilder, dpi, event::{Event, WindowEvent}};
 
@@ -7,6 +9,43 @@ mod im_state;
 enum ActionTaken {
     Send,
     Clear,
+    Open(PathBuf),
+}
+
+struct Args {
+    transport: TransportKind,
+    /// Image to send instead of the default one
+    image: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> Args {
+        let mut args = Args {
+            transport: TransportKind::Channel,
+            image: None,
+        };
+
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--transport" => match argv.next().as_deref().and_then(TransportKind::from_name) {
+                    Some(transport) => args.transport = transport,
+                    None => eprintln!("--transport expects one of: {}", transport_names()),
+                },
+                other if other.starts_with("--") => eprintln!("Ignoring unknown argument {other}"),
+                path => args.image = Some(PathBuf::from(path)),
+            }
+        }
+
+        args
+    }
+}
+
+fn transport_names() -> String {
+    TransportKind::ALL.iter()
+        .map(|kind| kind.name())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 fn main() {
@@ -14,6 +53,7 @@ fn main() {
         ..Default::default()
     };
     env_logger::init();
+    let args = Args::parse();
     let event_loop = EventLoop::new().unwrap();
     let wb = WindowBuilder::new()
         .with_title("Carga progresiva")
@@ -22,7 +62,7 @@ fn main() {
     let window = wb.build(&event_loop)
         .unwrap();
 
-    let mut im_state = pollster::block_on(ImState::new(&window, config.clone()));
+    let mut im_state = pollster::block_on(ImState::new(&window, config.clone(), args.transport, args.image));
     let mut last_render_time = std::time::Instant::now();
     event_loop.run(move |event, window_target| {
         window_target.set_control_flow(ControlFlow::Poll);
@@ -38,6 +78,7 @@ fn main() {
                     WindowEvent::CursorMoved { device_id: _, position: _, .. } => {im_state.input(&event);},
                     WindowEvent::MouseWheel { .. } => {im_state.input(&event);},
                     WindowEvent::MouseInput { .. } => {im_state.input(&event);},
+                    WindowEvent::DroppedFile(path) => im_state.open_image(path.clone()),
                     WindowEvent::RedrawRequested => {
                         let now = std::time::Instant::now();
                         let dt = now - last_render_time;
@@ -48,6 +89,7 @@ fn main() {
                                 match action {
                                     ActionTaken::Send => im_state.send(),
                                     ActionTaken::Clear => im_state.clear(),
+                                    ActionTaken::Open(path) => im_state.open_image(path),
                                 };
                             },
                             // Reconfigure the surface if lost